@@ -0,0 +1,290 @@
+// admission.rs - handshake admission control
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::errors::AdmissionError;
+use super::rate_limit::{TokenBucket, TokenBucketConfig};
+
+
+/// Addresses with nothing left to remember are forgotten once this
+/// many addresses are tracked.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// An admission control configuration type.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct AdmissionConfig {
+    /// The maximum number of handshakes in progress at once.
+    pub max_concurrent_handshakes: usize,
+    /// The rate of handshake attempts allowed from one address.
+    pub per_address: TokenBucketConfig,
+    /// Ban an address after this many consecutive authentication
+    /// failures.
+    pub max_authentication_failures: u32,
+    /// How long a ban lasts. Failures are also forgotten once this
+    /// long has passed since the last one.
+    pub ban_duration: Duration,
+    /// Give up on handshakes which take longer than this, so that
+    /// stalled peers do not hold on to a handshake slot.
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> AdmissionConfig {
+        AdmissionConfig {
+            max_concurrent_handshakes: 64,
+            per_address: TokenBucketConfig { rate: 1, burst: 10 },
+            max_authentication_failures: 5,
+            ban_duration: Duration::from_secs(600),
+            handshake_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+struct AddressState {
+    attempts: TokenBucket,
+    failures: u32,
+    last_failure: Option<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl AddressState {
+    fn new(config: TokenBucketConfig, now: Instant) -> AddressState {
+        AddressState {
+            attempts: TokenBucket::new(config, now),
+            failures: 0,
+            last_failure: None,
+            banned_until: None,
+        }
+    }
+
+    /// Forget failures older than the ban duration.
+    fn expire_failures(&mut self, ban_duration: Duration, now: Instant) {
+        if self.last_failure.map_or(false, |t| t + ban_duration <= now) {
+            self.failures = 0;
+            self.last_failure = None;
+        }
+    }
+}
+
+struct AdmissionState {
+    handshakes: usize,
+    addresses: HashMap<IpAddr, AddressState>,
+}
+
+/// AdmissionControl decides which inbound connections may start a
+/// handshake. Clones share the same state.
+#[derive(Clone)]
+pub struct AdmissionControl {
+    config: AdmissionConfig,
+    state: Arc<Mutex<AdmissionState>>,
+}
+
+impl AdmissionControl {
+    pub fn new(config: AdmissionConfig) -> AdmissionControl {
+        AdmissionControl {
+            config,
+            state: Arc::new(Mutex::new(AdmissionState {
+                handshakes: 0,
+                addresses: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn config(&self) -> &AdmissionConfig {
+        &self.config
+    }
+
+    /// Admit a handshake from the given address. The returned permit
+    /// holds a handshake slot until it is dropped.
+    pub fn admit(&self, address: IpAddr) -> Result<HandshakePermit, AdmissionError> {
+        self.admit_at(address, Instant::now())
+    }
+
+    fn admit_at(&self, address: IpAddr, now: Instant) -> Result<HandshakePermit, AdmissionError> {
+        let mut state = self.state.lock().unwrap();
+        if state.addresses.len() >= PRUNE_THRESHOLD {
+            let ban_duration = self.config.ban_duration;
+            state.addresses.retain(|_, x| {
+                x.expire_failures(ban_duration, now);
+                x.failures > 0 || x.banned_until.map_or(false, |t| t > now) || !x.attempts.is_full(now)
+            });
+        }
+        if let Some(entry) = state.addresses.get_mut(&address) {
+            if let Some(until) = entry.banned_until {
+                if until > now {
+                    return Err(AdmissionError::BannedError)
+                }
+                entry.banned_until = None;
+            }
+        }
+        // Refusing a connection for lack of a slot must not cost the
+        // address one of its attempts.
+        if state.handshakes >= self.config.max_concurrent_handshakes {
+            return Err(AdmissionError::TooManyHandshakesError)
+        }
+        let per_address = self.config.per_address;
+        let entry = state.addresses.entry(address).or_insert_with(|| AddressState::new(per_address, now));
+        if !entry.attempts.try_take(now) {
+            return Err(AdmissionError::RateLimitedError)
+        }
+        state.handshakes += 1;
+        Ok(HandshakePermit {
+            control: self.clone(),
+            address,
+            at: now,
+        })
+    }
+
+    pub fn ban(&self, address: IpAddr) {
+        self.ban_at(address, Instant::now())
+    }
+
+    fn ban_at(&self, address: IpAddr, now: Instant) {
+        let per_address = self.config.per_address;
+        let mut state = self.state.lock().unwrap();
+        let entry = state.addresses.entry(address).or_insert_with(|| AddressState::new(per_address, now));
+        entry.failures = 0;
+        entry.last_failure = None;
+        entry.banned_until = Some(now + self.config.ban_duration);
+    }
+
+    pub fn unban(&self, address: IpAddr) {
+        if let Some(entry) = self.state.lock().unwrap().addresses.get_mut(&address) {
+            entry.failures = 0;
+            entry.last_failure = None;
+            entry.banned_until = None;
+        }
+    }
+
+    pub fn is_banned(&self, address: IpAddr) -> bool {
+        let now = Instant::now();
+        match self.state.lock().unwrap().addresses.get(&address) {
+            Some(entry) => entry.banned_until.map_or(false, |t| t > now),
+            None => false,
+        }
+    }
+
+    /// Returns the number of handshakes in progress.
+    pub fn handshakes(&self) -> usize {
+        self.state.lock().unwrap().handshakes
+    }
+
+    fn record_result(&self, address: IpAddr, authenticated: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let banned = match state.addresses.get_mut(&address) {
+            Some(entry) => {
+                if authenticated {
+                    entry.failures = 0;
+                    entry.last_failure = None;
+                } else {
+                    entry.expire_failures(self.config.ban_duration, now);
+                    entry.failures += 1;
+                    entry.last_failure = Some(now);
+                }
+                entry.failures >= self.config.max_authentication_failures
+            },
+            None => false,
+        };
+        drop(state);
+        if banned {
+            self.ban_at(address, now);
+        }
+    }
+}
+
+/// HandshakePermit is a handshake slot granted by `AdmissionControl`.
+pub struct HandshakePermit {
+    control: AdmissionControl,
+    address: IpAddr,
+    at: Instant,
+}
+
+impl HandshakePermit {
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    /// Record that the peer authenticated, clearing its failure count.
+    pub fn authenticated(self) {
+        self.control.record_result(self.address, true, self.at);
+    }
+
+    /// Record that the peer failed to authenticate, banning it if it
+    /// has failed too many times in a row.
+    pub fn authentication_failed(self) {
+        self.control.record_result(self.address, false, self.at);
+    }
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        self.control.state.lock().unwrap().handshakes -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admission_test() {
+        let control = AdmissionControl::new(AdmissionConfig {
+            max_concurrent_handshakes: 2,
+            per_address: TokenBucketConfig { rate: 1, burst: 3 },
+            max_authentication_failures: 2,
+            ban_duration: Duration::from_secs(60),
+            handshake_timeout: None,
+        });
+        let now = Instant::now();
+        let alice: IpAddr = "192.0.2.1".parse().unwrap();
+        let bob: IpAddr = "192.0.2.2".parse().unwrap();
+        let mallory: IpAddr = "192.0.2.3".parse().unwrap();
+
+        // Concurrency limit, refusals cost mallory none of its attempts.
+        let a = control.admit_at(alice, now).unwrap();
+        let b = control.admit_at(bob, now).unwrap();
+        for _ in 0..5 {
+            assert_eq!(control.admit_at(mallory, now).err(), Some(AdmissionError::TooManyHandshakesError));
+        }
+        drop(a);
+        b.authenticated();
+        assert_eq!(control.handshakes(), 0);
+
+        // Per address rate limit, alice has two attempts left.
+        control.admit_at(alice, now).unwrap();
+        control.admit_at(alice, now).unwrap();
+        assert_eq!(control.admit_at(alice, now).err(), Some(AdmissionError::RateLimitedError));
+        control.admit_at(alice, now + Duration::from_secs(1)).unwrap();
+
+        // Ban after repeated authentication failures.
+        drop(control.admit_at(mallory, now).unwrap());
+        control.admit_at(mallory, now).unwrap().authentication_failed();
+        control.admit_at(mallory, now).unwrap().authentication_failed();
+        assert!(control.is_banned(mallory));
+        assert_eq!(control.admit_at(mallory, now).err(), Some(AdmissionError::BannedError));
+        control.admit_at(mallory, now + Duration::from_secs(61)).unwrap();
+
+        // Failures further apart than the ban duration do not add up.
+        let later = now + Duration::from_secs(100);
+        control.admit_at(bob, later).unwrap().authentication_failed();
+        control.admit_at(bob, later + Duration::from_secs(61)).unwrap().authentication_failed();
+        control.admit_at(bob, later + Duration::from_secs(62)).unwrap();
+    }
+}
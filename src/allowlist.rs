@@ -0,0 +1,185 @@
+// allowlist.rs - shared peer allowlists
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use ecdh_wrapper::PublicKey;
+
+use super::errors::HandshakeError;
+use super::logging::fingerprint;
+use super::transport::Transport;
+
+
+struct AllowlistState {
+    keys: HashSet<PublicKey>,
+    terminate_revoked: bool,
+    next_id: u64,
+    sessions: HashMap<u64, (PublicKey, Box<dyn Transport>)>,
+}
+
+impl AllowlistState {
+    fn terminate_revoked_sessions(&mut self) {
+        let keys = &self.keys;
+        self.sessions.retain(|_, &mut (ref key, ref transport)| {
+            if keys.contains(key) {
+                return true
+            }
+            log_info!("terminating session with revoked peer {}", fingerprint(key));
+            let _ = transport.shutdown_transport();
+            false
+        });
+    }
+}
+
+/// Allowlist is the set of peer keys an authenticator accepts.
+/// Clones share the same set, so keys added or removed through any
+/// clone, e.g. after fetching a new consensus, apply to every later
+/// handshake of the sessions and listeners configured with it.
+#[derive(Clone)]
+pub struct Allowlist {
+    state: Arc<Mutex<AllowlistState>>,
+}
+
+impl Allowlist {
+    pub fn new() -> Allowlist {
+        Allowlist::from_keys(vec![])
+    }
+
+    pub fn from_keys<I: IntoIterator<Item = PublicKey>>(keys: I) -> Allowlist {
+        Allowlist {
+            state: Arc::new(Mutex::new(AllowlistState {
+                keys: keys.into_iter().collect(),
+                terminate_revoked: false,
+                next_id: 0,
+                sessions: HashMap::new(),
+            })),
+        }
+    }
+
+    /// When enabled, removing a key closes the established sessions
+    /// with that peer. Only sessions which complete their handshake
+    /// while this is enabled are closed.
+    pub fn set_terminate_revoked(&self, enabled: bool) {
+        self.state.lock().unwrap().terminate_revoked = enabled;
+    }
+
+    pub fn terminate_revoked(&self) -> bool {
+        self.state.lock().unwrap().terminate_revoked
+    }
+
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.state.lock().unwrap().keys.contains(key)
+    }
+
+    /// Add a key, returning false if it was already present.
+    pub fn insert(&self, key: PublicKey) -> bool {
+        self.state.lock().unwrap().keys.insert(key)
+    }
+
+    /// Remove a key, returning false if it was not present.
+    pub fn remove(&self, key: &PublicKey) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.keys.remove(key) {
+            return false
+        }
+        state.terminate_revoked_sessions();
+        true
+    }
+
+    /// Atomically replace the whole set of keys.
+    pub fn replace<I: IntoIterator<Item = PublicKey>>(&self, keys: I) {
+        let keys = keys.into_iter().collect();
+        let mut state = self.state.lock().unwrap();
+        state.keys = keys;
+        state.terminate_revoked_sessions();
+    }
+
+    pub fn keys(&self) -> Vec<PublicKey> {
+        self.state.lock().unwrap().keys.iter().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of established sessions which will be
+    /// closed if their peer is removed.
+    pub fn sessions(&self) -> usize {
+        self.state.lock().unwrap().sessions.len()
+    }
+
+    /// Track an established session with the given peer so that it
+    /// can be closed once the peer is removed. Fails if the peer was
+    /// removed while its handshake was in progress, whether or not
+    /// revoked sessions are terminated.
+    pub(crate) fn register(&self, key: &PublicKey, transport: &dyn Transport) -> Result<Option<Registration>, HandshakeError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.keys.contains(key) {
+            return Err(HandshakeError::PeerRevokedError)
+        }
+        if !state.terminate_revoked {
+            return Ok(None)
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.sessions.insert(id, (key.clone(), transport.try_clone_transport()?));
+        Ok(Some(Registration {
+            allowlist: self.clone(),
+            id,
+        }))
+    }
+}
+
+impl Default for Allowlist {
+    fn default() -> Allowlist {
+        Allowlist::new()
+    }
+}
+
+impl PartialEq for Allowlist {
+    fn eq(&self, other: &Allowlist) -> bool {
+        if Arc::ptr_eq(&self.state, &other.state) {
+            return true
+        }
+        let keys = self.state.lock().unwrap().keys.clone();
+        keys == other.state.lock().unwrap().keys
+    }
+}
+
+impl fmt::Debug for Allowlist {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.state.lock().unwrap().keys.iter()).finish()
+    }
+}
+
+/// Registration keeps a session tracked by an `Allowlist` until it
+/// is dropped.
+pub(crate) struct Registration {
+    allowlist: Allowlist,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.allowlist.state.lock().unwrap().sessions.remove(&self.id);
+    }
+}
@@ -0,0 +1,152 @@
+// mix_link-decode.rs - command decoding tool
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate byteorder;
+extern crate mix_link;
+extern crate rustc_serialize;
+extern crate serde_json;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::process;
+
+use byteorder::{ByteOrder, BigEndian};
+use mix_link::commands::{Command, ConsensusStatus, DescriptorStatus, VoteStatus};
+use rustc_serialize::hex::{FromHex, ToHex};
+
+
+const USAGE: &str = "usage:
+    mix_link-decode [--raw] [file]    decode a plaintext command given in hex, or raw with --raw
+    mix_link-decode --encode [file]   encode a command described in JSON and print it in hex
+
+Input is read from stdin if no file is given. Commands are described in
+JSON by their variant name, with byte strings in hex, e.g.
+    {\"GetConsensus\": {\"epoch\": 1234}}
+    {\"SendPacket\": {\"sphinx_packet\": \"00ff...\"}}";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (mode, path) = match args.len() {
+        0 => ("--hex", None),
+        1 if args[0].starts_with("--") => (args[0].as_str(), None),
+        1 => ("--hex", Some(args[0].as_str())),
+        2 => (args[0].as_str(), Some(args[1].as_str())),
+        _ => usage(),
+    };
+    let input = match read_input(path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("mix_link-decode: {}", e);
+            process::exit(1);
+        },
+    };
+    let ok = match mode {
+        "--hex" => match String::from_utf8(input).ok().and_then(|x| x.split_whitespace().collect::<String>().from_hex().ok()) {
+            Some(bytes) => decode(&bytes),
+            None => {
+                eprintln!("mix_link-decode: input is not hex, use --raw for binary input");
+                false
+            },
+        },
+        "--raw" => decode(&input),
+        "--encode" => encode(&input),
+        _ => usage(),
+    };
+    if !ok {
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn read_input(path: Option<&str>) -> io::Result<Vec<u8>> {
+    let mut input = vec![];
+    match path {
+        Some(path) => File::open(path)?.read_to_end(&mut input)?,
+        None => io::stdin().read_to_end(&mut input)?,
+    };
+    Ok(input)
+}
+
+fn decode(b: &[u8]) -> bool {
+    if b.len() >= 6 {
+        println!("command id: {}", b[0]);
+        println!("length: {} bytes, {} bytes given", BigEndian::read_u32(&b[2..6]), b.len() - 6);
+    }
+    let cmd = match Command::from_bytes(b) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("error: {:?}: {}", e, e);
+            return false
+        },
+    };
+    println!("command: {}", cmd.name());
+    match cmd {
+        Command::NoOp{} | Command::Disconnect{} => {},
+        Command::GetConsensus { epoch } => println!("  epoch: {}", epoch),
+        Command::Consensus { error_code, ref payload } => {
+            println!("  error_code: {} ({:?})", error_code, ConsensusStatus::from_u8(error_code));
+            println!("  payload: {} bytes", payload.len());
+        },
+        Command::PostDescriptor { epoch, ref payload } => {
+            println!("  epoch: {}", epoch);
+            println!("  payload: {} bytes", payload.len());
+        },
+        Command::PostDescriptorStatus { error_code } => {
+            println!("  error_code: {} ({:?})", error_code, DescriptorStatus::from_u8(error_code));
+        },
+        Command::VoteStatus { error_code } => {
+            println!("  error_code: {} ({:?})", error_code, VoteStatus::from_u8(error_code));
+        },
+        Command::Vote { epoch, ref public_key, ref payload } => {
+            println!("  epoch: {}", epoch);
+            println!("  public_key: {}", public_key.to_vec().to_hex());
+            println!("  payload: {} bytes", payload.len());
+        },
+        Command::SendPacket { ref sphinx_packet } => println!("  sphinx_packet: {} bytes", sphinx_packet.len()),
+        Command::RetrieveMessage { sequence } | Command::MessageEmpty { sequence } => println!("  sequence: {}", sequence),
+        Command::MessageAck { queue_size_hint, sequence, ref id, ref payload } => {
+            println!("  queue_size_hint: {}", queue_size_hint);
+            println!("  sequence: {}", sequence);
+            println!("  id: {}", id.to_hex());
+            println!("  payload: {} bytes", payload.len());
+        },
+        Command::MessageMessage { queue_size_hint, sequence, ref payload } => {
+            println!("  queue_size_hint: {}", queue_size_hint);
+            println!("  sequence: {}", sequence);
+            println!("  payload: {} bytes", payload.len());
+        },
+    }
+    // Decoding succeeded, so any padding is valid.
+    println!("padding: {} bytes, valid", b.len() - cmd.to_vec().len());
+    true
+}
+
+fn encode(input: &[u8]) -> bool {
+    let cmd: Command = match serde_json::from_slice(input) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("mix_link-decode: invalid command: {}", e);
+            return false
+        },
+    };
+    println!("{}", cmd.to_vec().to_hex());
+    true
+}
@@ -0,0 +1,78 @@
+// mix_link-keygen.rs - link key management tool
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate ecdh_wrapper;
+extern crate mix_link;
+extern crate rand;
+extern crate rustc_serialize;
+
+use std::env;
+use std::error::Error;
+use std::process;
+
+use ecdh_wrapper::{PrivateKey, PublicKey};
+use mix_link::messages::AuthenticationKey;
+use mix_link::keyfile::{save_private_key, load_private_key, save_public_key, load_public_key};
+use rand::os::OsRng;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::hex::ToHex;
+
+
+const USAGE: &str = "usage:
+    mix_link-keygen generate <name>     write a new keypair to <name>.private and <name>.public
+    mix_link-keygen public <file>       print the public key of a private key file
+    mix_link-keygen inspect <file>      print a public key file in hex and base64";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match (args.get(0).map(|x| x.as_str()), args.get(1)) {
+        (Some("generate"), Some(name)) if args.len() == 2 => generate(name),
+        (Some("public"), Some(path)) if args.len() == 2 => derive_public(path),
+        (Some("inspect"), Some(path)) if args.len() == 2 => inspect(path),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("mix_link-keygen: {}", e);
+        process::exit(1);
+    }
+}
+
+fn generate(name: &str) -> Result<(), Box<dyn Error>> {
+    let mut rng = OsRng::new()?;
+    let private_key = AuthenticationKey::from(PrivateKey::generate(&mut rng).map_err(|_| "failed to generate key")?);
+    save_private_key(format!("{}.private", name), &private_key)?;
+    save_public_key(format!("{}.public", name), &private_key.public_key())?;
+    print_public_key(&private_key.public_key());
+    Ok(())
+}
+
+fn derive_public(path: &str) -> Result<(), Box<dyn Error>> {
+    print_public_key(&load_private_key(path)?.public_key());
+    Ok(())
+}
+
+fn inspect(path: &str) -> Result<(), Box<dyn Error>> {
+    print_public_key(&load_public_key(path)?);
+    Ok(())
+}
+
+fn print_public_key(key: &PublicKey) {
+    println!("hex:    {}", key.to_vec().to_hex());
+    println!("base64: {}", key.to_vec().to_base64(STANDARD));
+}
@@ -0,0 +1,307 @@
+// mix_link-probe.rs - link diagnostic tool
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate ecdh_wrapper;
+extern crate mix_link;
+extern crate rand;
+extern crate rustc_serialize;
+extern crate zeroize;
+
+use std::env;
+use std::fs;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process;
+use std::str;
+use std::time::{Duration, Instant};
+
+use ecdh_wrapper::{PrivateKey, PublicKey};
+use mix_link::commands::Command;
+use mix_link::constants::{SUPPORTED_PROTOCOL_VERSIONS, PSK_SIZE};
+use mix_link::errors::HandshakeError;
+use mix_link::keyfile::{load_private_key, load_public_key, parse_public_key};
+use mix_link::messages::{SessionConfig, AuthenticationKey, PeerAuthenticator};
+use mix_link::messages::{ClientAuthenticatorState, ServerAuthenticatorState, ProviderAuthenticatorState};
+use mix_link::rate_limit::RateLimits;
+use mix_link::suite::{NoiseSuite, NoiseCipher, NoiseHash};
+use mix_link::sync::Session;
+use rand::os::OsRng;
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::hex::{FromHex, ToHex};
+use zeroize::{Zeroize, Zeroizing};
+
+
+const USAGE: &str = "usage: mix_link-probe <address> <peer public key> [options]
+
+The peer public key is given in hex, base64 or as a public key file.
+
+options:
+    --key <file>              authenticate with this private key instead of a new one
+    --additional-data <text>  additional data to send, e.g. a username
+    --role <role>             probe as a client, server (mix) or provider, client by default
+    --psk-file <file>         use the pre-shared key in this file, given in hex or base64
+    --suite <suite>           the noise suite, ChaChaPoly_BLAKE2b by default, or one of
+                              ChaChaPoly_SHA256, AESGCM_BLAKE2b and AESGCM_SHA256
+    --versions <list>         comma separated protocol versions to offer, e.g. 0,1
+    --get-consensus <epoch>   request the consensus for an epoch after the handshake
+    --timeout <seconds>       network timeout, 10 seconds by default";
+
+struct Options {
+    address: String,
+    peer_key: PublicKey,
+    private_key: AuthenticationKey,
+    additional_data: Vec<u8>,
+    role: String,
+    psk: Option<Zeroizing<[u8; PSK_SIZE]>>,
+    suite: NoiseSuite,
+    versions: Vec<u8>,
+    consensus_epoch: Option<u64>,
+    timeout: Duration,
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        },
+    };
+    if !probe(options) {
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    if args.len() < 2 {
+        return Err("missing arguments".to_string());
+    }
+    let peer_key = if Path::new(&args[1]).is_file() {
+        load_public_key(&args[1]).map_err(|e| format!("{}: {}", args[1], e))?
+    } else {
+        parse_public_key(&args[1]).map_err(|e| format!("peer public key: {}", e))?
+    };
+    let mut key_file = None;
+    let mut additional_data = vec![];
+    let mut role = "client".to_string();
+    let mut psk = None;
+    let mut suite = NoiseSuite::default();
+    let mut versions = SUPPORTED_PROTOCOL_VERSIONS.to_vec();
+    let mut consensus_epoch = None;
+    let mut timeout = Duration::from_secs(10);
+    let mut rest = args[2..].iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().ok_or_else(|| format!("{} requires a value", flag))?;
+        match flag.as_str() {
+            "--key" => key_file = Some(value.clone()),
+            "--additional-data" => additional_data = value.clone().into_bytes(),
+            "--role" => {
+                match value.as_str() {
+                    "client" | "server" | "provider" => role = value.clone(),
+                    _ => return Err(format!("unknown role {}, expected client, server or provider", value)),
+                }
+            },
+            "--psk-file" => psk = Some(load_psk(value).map_err(|e| format!("{}: {}", value, e))?),
+            "--suite" => suite = parse_suite(value)?,
+            "--versions" => {
+                versions = value.split(',').map(|x| x.trim().parse()).collect::<Result<_, _>>()
+                    .map_err(|_| format!("invalid versions {}", value))?;
+            },
+            "--get-consensus" => {
+                consensus_epoch = Some(value.parse().map_err(|_| format!("invalid epoch {}", value))?);
+            },
+            "--timeout" => {
+                timeout = Duration::from_secs(value.parse().map_err(|_| format!("invalid timeout {}", value))?);
+            },
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+    let private_key = match key_file {
+        Some(path) => load_private_key(&path).map_err(|e| format!("{}: {}", path, e))?,
+        None => {
+            let mut rng = OsRng::new().map_err(|e| e.to_string())?;
+            PrivateKey::generate(&mut rng).map_err(|_| "failed to generate a key".to_string())?.into()
+        },
+    };
+    Ok(Options {
+        address: args[0].clone(),
+        peer_key,
+        private_key,
+        additional_data,
+        role,
+        psk,
+        suite,
+        versions,
+        consensus_epoch,
+        timeout,
+    })
+}
+
+fn parse_suite(name: &str) -> Result<NoiseSuite, String> {
+    let (cipher, hash) = match name.find('_') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => return Err(format!("invalid suite {}", name)),
+    };
+    let cipher = match cipher {
+        "ChaChaPoly" => NoiseCipher::ChaChaPoly,
+        "AESGCM" => NoiseCipher::AesGcm,
+        _ => return Err(format!("unknown cipher {}", cipher)),
+    };
+    let hash = match hash {
+        "BLAKE2b" => NoiseHash::Blake2b,
+        "SHA256" => NoiseHash::Sha256,
+        _ => return Err(format!("unknown hash {}", hash)),
+    };
+    Ok(NoiseSuite { cipher, hash })
+}
+
+fn load_psk(path: &str) -> Result<Zeroizing<[u8; PSK_SIZE]>, String> {
+    let mut text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let raw = {
+        let trimmed = text.trim();
+        if trimmed.len() == PSK_SIZE * 2 {
+            trimmed.from_hex().ok()
+        } else {
+            trimmed.from_base64().ok()
+        }
+    };
+    text.zeroize();
+    let mut raw = raw.ok_or_else(|| "the pre-shared key is not hex or base64 encoded".to_string())?;
+    let mut psk = Zeroizing::new([0u8; PSK_SIZE]);
+    let result = if raw.len() == PSK_SIZE {
+        psk.copy_from_slice(&raw);
+        Ok(psk)
+    } else {
+        Err(format!("the pre-shared key must be {} bytes", PSK_SIZE))
+    };
+    raw.zeroize();
+    result
+}
+
+/// Run the probe, printing each step. Returns false if a step failed.
+fn probe(options: Options) -> bool {
+    println!("local public key: {}", options.private_key.public_key().to_vec().to_hex());
+    // The peer is the only key we accept, whichever role we probe as.
+    let authenticator = match options.role.as_str() {
+        "server" => {
+            let state = ServerAuthenticatorState::default();
+            state.mix_map.insert(options.peer_key.clone());
+            PeerAuthenticator::Server(state)
+        },
+        "provider" => {
+            let state = ProviderAuthenticatorState::default();
+            state.mix_map.insert(options.peer_key.clone());
+            PeerAuthenticator::Provider(state)
+        },
+        _ => {
+            let mut state = ClientAuthenticatorState::default();
+            state.peer_public_keys = vec![options.peer_key.clone()];
+            PeerAuthenticator::Client(state)
+        },
+    };
+    let config = SessionConfig {
+        authenticator,
+        authentication_key: options.private_key,
+        peer_public_key: Some(options.peer_key.clone()),
+        additional_data: options.additional_data,
+        replay_cache: None,
+        protocol_versions: options.versions,
+        noise_suite: options.suite,
+        psk: options.psk,
+        clock: None,
+        rng: None,
+        rate_limits: RateLimits::default(),
+        metrics: None,
+    };
+
+    // connect
+    let start = Instant::now();
+    let address = match options.address.to_socket_addrs().map(|mut x| x.next()) {
+        Ok(Some(x)) => x,
+        Ok(None) => return failed("resolve", "no addresses found"),
+        Err(e) => return failed("resolve", &e),
+    };
+    let stream = match TcpStream::connect_timeout(&address, options.timeout) {
+        Ok(x) => x,
+        Err(e) => return failed("connect", &e),
+    };
+    if let Err(e) = stream.set_read_timeout(Some(options.timeout)) {
+        return failed("connect", &e);
+    }
+    if let Err(e) = stream.set_write_timeout(Some(options.timeout)) {
+        return failed("connect", &e);
+    }
+    println!("connected to {} in {:?}", address, start.elapsed());
+
+    // handshake
+    let start = Instant::now();
+    let mut session = match Session::new(config, true) {
+        Ok(x) => x,
+        Err(e) => return handshake_failed(&e),
+    };
+    if let Err(e) = session.initialize(stream) {
+        return handshake_failed(&e);
+    }
+    session = match session.into_transport_mode() {
+        Ok(x) => x,
+        Err(e) => return handshake_failed(&e),
+    };
+    if let Err(e) = session.finalize_handshake() {
+        // The responder closes the link when it rejects us, which we
+        // only notice while waiting for its first message.
+        handshake_failed(&e);
+        println!("the peer most likely rejected our public key or additional data");
+        return false
+    }
+    println!("handshake completed in {:?}", start.elapsed());
+
+    let credentials = session.peer_credentials();
+    println!("peer public key: {}", credentials.public_key.to_vec().to_hex());
+    match str::from_utf8(&credentials.additional_data) {
+        Ok(x) => println!("peer additional data: {:?}", x),
+        Err(_) => println!("peer additional data: {}", credentials.additional_data.to_hex()),
+    }
+    println!("protocol version: {:?}", session.protocol_version());
+    println!("clock skew: {}s", session.clock_skew());
+
+    // consensus
+    if let Some(epoch) = options.consensus_epoch {
+        let start = Instant::now();
+        if let Err(e) = session.send_command(&Command::GetConsensus { epoch }) {
+            return failed("GetConsensus", &e);
+        }
+        match session.recv_command() {
+            Ok(Command::Consensus { error_code, payload }) => {
+                println!("consensus for epoch {}: error code {}, {} bytes in {:?}",
+                         epoch, error_code, payload.len(), start.elapsed());
+            },
+            Ok(cmd) => println!("unexpected reply to GetConsensus: {}", cmd.name()),
+            Err(e) => return failed("GetConsensus", &e),
+        }
+    }
+    session.close();
+    true
+}
+
+fn handshake_failed(error: &HandshakeError) -> bool {
+    println!("handshake failed at {}: {}", error.name(), error);
+    false
+}
+
+fn failed<E: ::std::fmt::Display + ?Sized>(step: &str, error: &E) -> bool {
+    println!("failed at {}: {}", step, error);
+    false
+}
@@ -0,0 +1,234 @@
+// cipherstate.rs - transport cipher states
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use blake2_rfc::blake2b::Blake2b;
+use snow;
+use snow::params::{DHChoice, HashChoice, CipherChoice};
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::types::{Random, Dh, Hash, Cipher};
+use zeroize::Zeroize;
+
+use super::constants::{KEY_SIZE, MAC_SIZE};
+use super::errors::HandshakeError;
+
+
+/// CipherState is one direction of an established link, a cipher
+/// keyed at the end of the handshake and the nonce of its next
+/// message. Each half of a split session owns one.
+pub struct CipherState {
+    cipher: Box<dyn Cipher + Send>,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new(choice: &CipherChoice, key: &[u8]) -> Result<CipherState, HandshakeError> {
+        let mut cipher = match DefaultResolver.resolve_cipher(choice) {
+            Some(x) => x,
+            None => return Err(HandshakeError::InvalidNoiseSpecError),
+        };
+        cipher.set(key);
+        Ok(CipherState {
+            cipher,
+            nonce: 0,
+        })
+    }
+
+    /// Encrypt a message of at most `out.len() - MAC_SIZE` bytes
+    /// under the next nonce, returning the ciphertext length.
+    pub fn encrypt(&mut self, plaintext: &[u8], out: &mut [u8]) -> usize {
+        let len = self.cipher.encrypt(self.nonce, &[], plaintext, out);
+        self.nonce += 1;
+        len
+    }
+
+    /// Decrypt a message under the next nonce, which only advances
+    /// if the message is authentic.
+    pub fn decrypt(&mut self, ciphertext: &[u8], out: &mut [u8]) -> Result<usize, ()> {
+        if ciphertext.len() < MAC_SIZE || out.len() < ciphertext.len() - MAC_SIZE {
+            return Err(())
+        }
+        let len = self.cipher.decrypt(self.nonce, &[], ciphertext, out)?;
+        self.nonce += 1;
+        Ok(len)
+    }
+}
+
+impl fmt::Debug for CipherState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CipherState {{ nonce: {}, <redacted> }}", self.nonce)
+    }
+}
+
+/// The transport of a finished handshake, split into a cipher state
+/// for each direction.
+pub struct Split {
+    pub sender: CipherState,
+    pub receiver: CipherState,
+    /// A secret derived from both transport keys, unlike the
+    /// handshake hash it cannot be computed from the transcript.
+    pub exporter_secret: Vec<u8>,
+}
+
+struct KeySlots(Vec<[u8; KEY_SIZE]>);
+
+impl Drop for KeySlots {
+    fn drop(&mut self) {
+        for key in self.0.iter_mut() {
+            key.zeroize();
+        }
+    }
+}
+
+/// RecordedKeys holds the last key set on each cipher handed out by
+/// a KeyRecordingResolver. Clones share the same keys, which are
+/// wiped once the last clone is dropped.
+#[derive(Clone)]
+pub struct RecordedKeys {
+    keys: Arc<Mutex<KeySlots>>,
+}
+
+impl RecordedKeys {
+    fn set(&self, index: usize, key: &[u8]) {
+        let mut keys = self.keys.lock().unwrap();
+        keys.0[index].copy_from_slice(&key[..KEY_SIZE]);
+    }
+
+    /// Find the keys which a finished Noise session in stateless
+    /// transport mode sends and receives with, and return a cipher
+    /// state keyed with each. The probe messages are never sent.
+    pub fn split(&self, session: &snow::Session, choice: &CipherChoice) -> Result<Split, HandshakeError> {
+        let mut probe = [0u8; MAC_SIZE];
+        session.write_message_with_nonce(0, &[], &mut probe)?;
+        let keys = self.keys.lock().unwrap();
+        let mut sender = None;
+        let mut receiver = None;
+        for (i, key) in keys.0.iter().enumerate() {
+            let cipher = CipherState::new(choice, key)?;
+            let mut out = [0u8; MAC_SIZE];
+            cipher.cipher.encrypt(0, &[], &[], &mut out);
+            if sender.is_none() && out == probe {
+                sender = Some((i, cipher));
+            } else if receiver.is_none() && session.read_message_with_nonce(0, &out, &mut []).is_ok() {
+                receiver = Some((i, cipher));
+            }
+        }
+        let ((send_index, sender), (receive_index, receiver)) = match (sender, receiver) {
+            (Some(sender), Some(receiver)) => (sender, receiver),
+            _ => return Err(HandshakeError::InvalidStateError),
+        };
+        // Both ends key the exporter with the initiator's sending key
+        // followed by the responder's.
+        let mut key = [0u8; 2 * KEY_SIZE];
+        let (first, second) = if session.is_initiator() {
+            (send_index, receive_index)
+        } else {
+            (receive_index, send_index)
+        };
+        key[..KEY_SIZE].copy_from_slice(&keys.0[first]);
+        key[KEY_SIZE..].copy_from_slice(&keys.0[second]);
+        let mut hasher = Blake2b::with_key(64, &key);
+        hasher.update(b"mix_link exporter secret");
+        let exporter_secret = hasher.finalize().as_bytes().to_vec();
+        key.zeroize();
+        Ok(Split {
+            sender,
+            receiver,
+            exporter_secret,
+        })
+    }
+}
+
+impl fmt::Debug for RecordedKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RecordedKeys {{ <redacted> }}")
+    }
+}
+
+struct RecordingCipher {
+    inner: Box<dyn Cipher + Send>,
+    keys: RecordedKeys,
+    index: usize,
+}
+
+impl Cipher for RecordingCipher {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn set(&mut self, key: &[u8]) {
+        self.inner.set(key);
+        self.keys.set(self.index, key);
+    }
+
+    fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize {
+        self.inner.encrypt(nonce, authtext, plaintext, out)
+    }
+
+    fn decrypt(&self, nonce: u64, authtext: &[u8], ciphertext: &[u8], out: &mut [u8]) -> Result<usize, ()> {
+        self.inner.decrypt(nonce, authtext, ciphertext, out)
+    }
+}
+
+/// KeyRecordingResolver records the keys set on the ciphers of a
+/// handshake, snow offers no other way to take the keys split at its
+/// end. All other primitives come from the wrapped resolver.
+pub struct KeyRecordingResolver {
+    parent: Box<dyn CryptoResolver>,
+    keys: RecordedKeys,
+}
+
+impl KeyRecordingResolver {
+    pub fn new(parent: Box<dyn CryptoResolver>) -> KeyRecordingResolver {
+        KeyRecordingResolver {
+            parent,
+            keys: RecordedKeys {
+                keys: Arc::new(Mutex::new(KeySlots(vec![]))),
+            },
+        }
+    }
+
+    pub fn keys(&self) -> RecordedKeys {
+        self.keys.clone()
+    }
+}
+
+impl CryptoResolver for KeyRecordingResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random + Send>> {
+        self.parent.resolve_rng()
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh + Send>> {
+        self.parent.resolve_dh(choice)
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash + Send>> {
+        self.parent.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher + Send>> {
+        let inner = self.parent.resolve_cipher(choice)?;
+        let mut keys = self.keys.keys.lock().unwrap();
+        keys.0.push([0u8; KEY_SIZE]);
+        Some(Box::new(RecordingCipher {
+            inner,
+            keys: self.keys.clone(),
+            index: keys.0.len() - 1,
+        }))
+    }
+}
@@ -0,0 +1,81 @@
+// clock.rs - injectable time source
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+/// Clock is the time source used for handshake timestamps.
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// Returns the number of seconds since the unix epoch.
+    fn now(&self) -> u64;
+}
+
+/// SystemClock reads the system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+}
+
+/// ManualClock only moves when told to, which makes it possible to
+/// simulate clock skew between peers. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    time: Arc<Mutex<u64>>,
+}
+
+impl ManualClock {
+    pub fn new(time: u64) -> ManualClock {
+        ManualClock {
+            time: Arc::new(Mutex::new(time)),
+        }
+    }
+
+    pub fn set(&self, time: u64) {
+        *self.time.lock().unwrap() = time;
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        *self.time.lock().unwrap() += seconds;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        *self.time.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_test() {
+        let clock = ManualClock::new(1000);
+        let shared = clock.clone();
+        clock.advance(5);
+        assert_eq!(shared.now(), 1005);
+        shared.set(10);
+        assert_eq!(clock.now(), 10);
+        assert!(SystemClock.now() > 1_500_000_000);
+    }
+}
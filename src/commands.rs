@@ -117,11 +117,114 @@ pub const VOTE_MALFORMED: u8 = 5;
 // VOTE_ALREADY_RECEIVED signifies that the vote from that peer was already received.
 pub const VOTE_ALREADY_RECEIVED: u8 = 6;
 
+/// ConsensusStatus is the typed error code of a Consensus command.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialization", serde(rename_all = "snake_case"))]
+pub enum ConsensusStatus {
+    Ok,
+    NotFound,
+    Gone,
+}
+
+impl ConsensusStatus {
+    pub fn from_u8(code: u8) -> Option<ConsensusStatus> {
+        match code {
+            CONSENSUS_OK => Some(ConsensusStatus::Ok),
+            CONSENSUS_NOT_FOUND => Some(ConsensusStatus::NotFound),
+            CONSENSUS_GONE => Some(ConsensusStatus::Gone),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            ConsensusStatus::Ok => CONSENSUS_OK,
+            ConsensusStatus::NotFound => CONSENSUS_NOT_FOUND,
+            ConsensusStatus::Gone => CONSENSUS_GONE,
+        }
+    }
+}
+
+/// DescriptorStatus is the typed error code of a
+/// PostDescriptorStatus command.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialization", serde(rename_all = "snake_case"))]
+pub enum DescriptorStatus {
+    Ok,
+    Invalid,
+    Conflict,
+    Forbidden,
+}
+
+impl DescriptorStatus {
+    pub fn from_u8(code: u8) -> Option<DescriptorStatus> {
+        match code {
+            DESCRIPTOR_OK => Some(DescriptorStatus::Ok),
+            DESCRIPTOR_INVALID => Some(DescriptorStatus::Invalid),
+            DESCRIPTOR_CONFLICT => Some(DescriptorStatus::Conflict),
+            DESCRIPTOR_FORBIDDEN => Some(DescriptorStatus::Forbidden),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            DescriptorStatus::Ok => DESCRIPTOR_OK,
+            DescriptorStatus::Invalid => DESCRIPTOR_INVALID,
+            DescriptorStatus::Conflict => DESCRIPTOR_CONFLICT,
+            DescriptorStatus::Forbidden => DESCRIPTOR_FORBIDDEN,
+        }
+    }
+}
+
+/// VoteStatus is the typed error code of a VoteStatus command.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialization", serde(rename_all = "snake_case"))]
+pub enum VoteStatus {
+    Ok,
+    TooLate,
+    TooEarly,
+    NotAuthorized,
+    NotSigned,
+    Malformed,
+    AlreadyReceived,
+}
+
+impl VoteStatus {
+    pub fn from_u8(code: u8) -> Option<VoteStatus> {
+        match code {
+            VOTE_OK => Some(VoteStatus::Ok),
+            VOTE_TOO_LATE => Some(VoteStatus::TooLate),
+            VOTE_TOO_EARLY => Some(VoteStatus::TooEarly),
+            VOTE_NOT_AUTHORIZED => Some(VoteStatus::NotAuthorized),
+            VOTE_NOT_SIGNED => Some(VoteStatus::NotSigned),
+            VOTE_MALFORMED => Some(VoteStatus::Malformed),
+            VOTE_ALREADY_RECEIVED => Some(VoteStatus::AlreadyReceived),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            VoteStatus::Ok => VOTE_OK,
+            VoteStatus::TooLate => VOTE_TOO_LATE,
+            VoteStatus::TooEarly => VOTE_TOO_EARLY,
+            VoteStatus::NotAuthorized => VOTE_NOT_AUTHORIZED,
+            VoteStatus::NotSigned => VOTE_NOT_SIGNED,
+            VoteStatus::Malformed => VOTE_MALFORMED,
+            VoteStatus::AlreadyReceived => VOTE_ALREADY_RECEIVED,
+        }
+    }
+}
 
 
 #[derive(Clone)]
 #[derive(PartialEq)]
 #[derive(Debug)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub enum Command {
     NoOp {},
     GetConsensus {
@@ -129,10 +232,12 @@ pub enum Command {
     },
     Consensus {
         error_code: u8,
+        #[cfg_attr(feature = "serialization", serde(with = "::serialization::bytes"))]
         payload: Vec<u8>,
     },
     PostDescriptor {
         epoch: u64,
+        #[cfg_attr(feature = "serialization", serde(with = "::serialization::bytes"))]
         payload: Vec<u8>,
     },
     PostDescriptorStatus {
@@ -140,7 +245,9 @@ pub enum Command {
     },
     Vote {
         epoch: u64,
+        #[cfg_attr(feature = "serialization", serde(with = "::serialization::public_key"))]
         public_key: PublicKey,
+        #[cfg_attr(feature = "serialization", serde(with = "::serialization::bytes"))]
         payload: Vec<u8>,
     },
     VoteStatus {
@@ -148,6 +255,7 @@ pub enum Command {
     },
     Disconnect {},
     SendPacket {
+        #[cfg_attr(feature = "serialization", serde(with = "::serialization::bytes"))]
         sphinx_packet: Vec<u8>,
     },
     RetrieveMessage {
@@ -156,12 +264,15 @@ pub enum Command {
     MessageAck {
         queue_size_hint: u8,
         sequence: u32,
+        #[cfg_attr(feature = "serialization", serde(with = "::serialization::surb_id"))]
         id: [u8; SURB_ID_SIZE],
+        #[cfg_attr(feature = "serialization", serde(with = "::serialization::ack_payload"))]
         payload: Vec<u8>,
     },
     MessageMessage {
         queue_size_hint: u8,
         sequence: u32,
+        #[cfg_attr(feature = "serialization", serde(with = "::serialization::message_payload"))]
         payload: Vec<u8>,
     },
     MessageEmpty {
@@ -178,14 +289,13 @@ impl Command {
         if b[1] != 0 {
             return Err(CommandError::InvalidReservedByte);
         }
-        let cmd_len = BigEndian::read_u32(&b[2..6]);
-        let _cmd = &b[CMD_OVERHEAD..];
-        if _cmd.len() < cmd_len as usize {
-            let _padding = _cmd[cmd_len as usize..].to_vec();
-            let _zeros = vec![0u8; cmd_len as usize];
-            if _zeros.ct_eq(&_padding[..]).unwrap_u8() == 0 {
-                return Err(CommandError::MessageDecodeError);
-            }
+        let cmd_len = BigEndian::read_u32(&b[2..6]) as usize;
+        if b.len() - CMD_OVERHEAD < cmd_len {
+            return Err(CommandError::InvalidLengthError);
+        }
+        let (_cmd, _padding) = b[CMD_OVERHEAD..].split_at(cmd_len);
+        if !is_zero(_padding) {
+            return Err(CommandError::InvalidPaddingError);
         }
 
         // handle commands with no payload
@@ -200,19 +310,38 @@ impl Command {
         }
 
         match cmd_id {
-            SEND_PACKET => Ok(send_packet_from_bytes(_cmd).unwrap()),
-            RETRIEVE_MESSAGE => Ok(retrieve_message_from_bytes(_cmd).unwrap()),
-            MESSAGE => Ok(message_from_bytes(_cmd).unwrap()),
-            GET_CONSENSUS => Ok(get_consensus_from_bytes(_cmd).unwrap()),
-            CONSENSUS => Ok(consensus_from_bytes(_cmd).unwrap()),
-            POST_DESCRIPTOR => Ok(post_descriptor_from_bytes(_cmd).unwrap()),
-            POST_DESCRIPTOR_STATUS => Ok(post_descriptor_status_from_bytes(_cmd).unwrap()),
-            VOTE => Ok(vote_from_bytes(_cmd).unwrap()),
-            VOTE_STATUS => Ok(vote_status_from_bytes(_cmd).unwrap()),
+            SEND_PACKET => send_packet_from_bytes(_cmd),
+            RETRIEVE_MESSAGE => retrieve_message_from_bytes(_cmd),
+            MESSAGE => message_from_bytes(_cmd),
+            GET_CONSENSUS => get_consensus_from_bytes(_cmd),
+            CONSENSUS => consensus_from_bytes(_cmd),
+            POST_DESCRIPTOR => post_descriptor_from_bytes(_cmd),
+            POST_DESCRIPTOR_STATUS => post_descriptor_status_from_bytes(_cmd),
+            VOTE => vote_from_bytes(_cmd),
+            VOTE_STATUS => vote_status_from_bytes(_cmd),
             _ => Err(CommandError::MessageDecodeError),
         }
     }
 
+    /// Returns the name of the command type.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::NoOp{..} => "NoOp",
+            Command::GetConsensus{..} => "GetConsensus",
+            Command::Consensus{..} => "Consensus",
+            Command::PostDescriptor{..} => "PostDescriptor",
+            Command::PostDescriptorStatus{..} => "PostDescriptorStatus",
+            Command::Vote{..} => "Vote",
+            Command::VoteStatus{..} => "VoteStatus",
+            Command::Disconnect{..} => "Disconnect",
+            Command::SendPacket{..} => "SendPacket",
+            Command::RetrieveMessage{..} => "RetrieveMessage",
+            Command::MessageAck{..} => "MessageAck",
+            Command::MessageMessage{..} => "MessageMessage",
+            Command::MessageEmpty{..} => "MessageEmpty",
+        }
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
         match self {
             Command::NoOp{} => {
@@ -347,9 +476,15 @@ impl Command {
     }
 }
 
+/// Returns true if every byte is zero, in constant time.
+fn is_zero(b: &[u8]) -> bool {
+    let zeros = vec![0u8; b.len()];
+    zeros.ct_eq(b).unwrap_u8() == 1
+}
+
 fn get_consensus_from_bytes(b: &[u8]) -> Result<Command, CommandError> {
     if b.len() != GET_CONSENSUS_SIZE {
-        println!("wtfff {} != {}", b.len(), GET_CONSENSUS_SIZE);
+        log_debug!("invalid GetConsensus command size {} != {}", b.len(), GET_CONSENSUS_SIZE);
         return Err(CommandError::GetConsensusDecodeError);
     }
     Ok(Command::GetConsensus{
@@ -398,7 +533,9 @@ fn vote_from_bytes(b: &[u8]) -> Result<Command, CommandError> {
         return Err(CommandError::VoteDecodeError);
     }
     let mut _public_key = PublicKey::default();
-    _public_key.from_bytes(&b[8..40]).unwrap();
+    if _public_key.from_bytes(&b[8..VOTE_OVERHEAD]).is_err() {
+        return Err(CommandError::VoteDecodeError);
+    }
     Ok(Command::Vote{
         epoch: BigEndian::read_u64(&b[..8]),
         public_key: _public_key,
@@ -458,9 +595,8 @@ fn message_from_bytes(b: &[u8]) -> Result<Command, CommandError> {
                 return Err(CommandError::MessageDecodeError);
             }
 
-            let zeros = [0u8; USER_FORWARD_PAYLOAD_SIZE];
-            if zeros.ct_eq(&_msg[USER_FORWARD_PAYLOAD_SIZE..]).unwrap_u8() != 0 {
-                return Err(CommandError::MessageDecodeError);
+            if !is_zero(&_msg[USER_FORWARD_PAYLOAD_SIZE..]) {
+                return Err(CommandError::InvalidPaddingError);
             }
             let _msg = &_msg[..USER_FORWARD_PAYLOAD_SIZE];
             let _message = Command::MessageMessage {
@@ -474,9 +610,8 @@ fn message_from_bytes(b: &[u8]) -> Result<Command, CommandError> {
             if _msg.len() != MESSAGE_EMPTY_SIZE - MESSAGE_BASE_SIZE {
                 return Err(CommandError::MessageDecodeError);
             }
-            let zeros = [0u8; MESSAGE_EMPTY_SIZE - MESSAGE_BASE_SIZE];
-            if zeros.ct_eq(&_msg[MESSAGE_EMPTY_SIZE - MESSAGE_BASE_SIZE..]).unwrap_u8() != 0 {
-                return Err(CommandError::MessageDecodeError);
+            if !is_zero(_msg) {
+                return Err(CommandError::InvalidPaddingError);
             }
             Ok(Command::MessageEmpty{
                 sequence: _seq,
@@ -640,4 +775,42 @@ mod tests {
         let message_empty2_bytes = message_empty2.to_vec();
         assert_eq!(message_empty_bytes, message_empty2_bytes);
     }
+
+    #[test]
+    fn malformed_commands_test() {
+        let mut b = Command::GetConsensus { epoch: 1 }.to_vec();
+        b.truncate(b.len() - 1);
+        match Command::from_bytes(&b) {
+            Err(CommandError::InvalidLengthError) => {},
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let mut b = Command::RetrieveMessage { sequence: 1 }.to_vec();
+        b.extend_from_slice(&[0, 0]);
+        assert_eq!(Command::from_bytes(&b).unwrap(), Command::RetrieveMessage { sequence: 1 });
+        b.push(1);
+        match Command::from_bytes(&b) {
+            Err(CommandError::InvalidPaddingError) => {},
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let mut b = Command::MessageMessage {
+            queue_size_hint: 0,
+            sequence: 1,
+            payload: vec![0u8; USER_FORWARD_PAYLOAD_SIZE],
+        }.to_vec();
+        let last = b.len() - 1;
+        b[last] = 1;
+        match Command::from_bytes(&b) {
+            Err(CommandError::InvalidPaddingError) => {},
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        // Decoders report errors instead of panicking.
+        let b = [GET_CONSENSUS, 0, 0, 0, 0, 1, 0];
+        match Command::from_bytes(&b) {
+            Err(CommandError::GetConsensusDecodeError) => {},
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
 }
@@ -0,0 +1,405 @@
+// config.rs - TOML session configuration
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Loading a `SessionConfig` from a TOML file, e.g.
+//!
+//! ```toml
+//! role = "provider"
+//! private_key_file = "link.private"
+//! additional_data = "provider1"
+//!
+//! [replay]
+//! capacity = 65536
+//! window = 120
+//!
+//! [admission]
+//! max_concurrent_handshakes = 64
+//! handshake_timeout = 30
+//!
+//! [mixes]
+//! keys = ["ZV67wsfmEY6ryLt+RsBCNBc/pOXBw6T2rGnZ0ZvHEi4="]
+//! additional_data = "node_identity"
+//! terminate_revoked = true
+//!
+//! [clients]
+//! keys = ["faf15be9be867789095d5ace827be3eb5f2a1c1f9e3c6c15b522c8f56e200362"]
+//! additional_data = "username"
+//!
+//! [rate_limits.client]
+//! packets = { rate = 100, burst = 200 }
+//! control = { rate = 10, burst = 20 }
+//! policy = "drop"
+//! ```
+//!
+//! Clients pin their Provider's key with `peer_public_key`, or a
+//! list of keys with `peer_public_keys` while the Provider rotates
+//! its key. Public keys are given in hex or base64 and relative key
+//! file paths are relative to the directory of the configuration
+//! file.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use ecdh_wrapper::PublicKey;
+use toml;
+
+use super::admission::AdmissionConfig;
+use super::allowlist::Allowlist;
+use super::constants::{SUPPORTED_PROTOCOL_VERSIONS, MAX_PROTOCOL_VERSION, MAX_ADDITIONAL_DATA_SIZE};
+use super::errors::ConfigError;
+use super::keyfile::{load_private_key, parse_public_key as parse_key};
+use super::messages::{SessionConfig, PeerAuthenticator, AdditionalDataPolicy};
+use super::messages::{ClientAuthenticatorState, ServerAuthenticatorState, ProviderAuthenticatorState};
+use super::rate_limit::{RateLimits, RateLimitConfig, RateLimitPolicy, TokenBucketConfig};
+use super::replay::ReplayCache;
+use super::suite::NoiseSuite;
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    role: String,
+    private_key_file: String,
+    peer_public_key: Option<String>,
+    peer_public_keys: Option<Vec<String>>,
+    peer_additional_data: Option<String>,
+    additional_data: Option<String>,
+    protocol_versions: Option<Vec<u8>>,
+    replay: Option<RawReplay>,
+    admission: Option<RawAdmission>,
+    mixes: Option<RawAllowlist>,
+    clients: Option<RawAllowlist>,
+    rate_limits: Option<RawRateLimits>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawReplay {
+    capacity: usize,
+    window: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAdmission {
+    max_concurrent_handshakes: Option<usize>,
+    per_address: Option<RawBucket>,
+    max_authentication_failures: Option<u32>,
+    ban_duration: Option<u64>,
+    handshake_timeout: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAllowlist {
+    keys: Vec<String>,
+    additional_data: Option<String>,
+    terminate_revoked: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRateLimits {
+    client: Option<RawRateLimit>,
+    mix: Option<RawRateLimit>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRateLimit {
+    packets: RawBucket,
+    control: RawBucket,
+    policy: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBucket {
+    rate: u32,
+    burst: u32,
+}
+
+/// LinkConfig is a loaded configuration file.
+#[derive(Debug)]
+pub struct LinkConfig {
+    pub session: SessionConfig,
+    pub admission: AdmissionConfig,
+}
+
+/// Load a configuration file, reading the key files it refers to.
+pub fn load_config<P: AsRef<Path>>(path: P) -> Result<LinkConfig, ConfigError> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    parse_config(&text, path.parent().unwrap_or_else(|| Path::new(".")))
+}
+
+/// Parse a configuration, relative key file paths are resolved
+/// against `base_dir`.
+pub fn parse_config(text: &str, base_dir: &Path) -> Result<LinkConfig, ConfigError> {
+    let raw: RawConfig = toml::from_str(text).map_err(|e| ConfigError::ParseError(e.to_string()))?;
+
+    let peer_public_key = match raw.peer_public_key {
+        Some(ref x) => Some(parse_public_key("peer_public_key", x)?),
+        None => None,
+    };
+    let authenticator = match raw.role.as_str() {
+        "client" => {
+            if raw.mixes.is_some() || raw.clients.is_some() {
+                return Err(ConfigError::InvalidFieldError("role", "clients do not have allowlists".to_string()));
+            }
+            let mut peer_public_keys: Vec<PublicKey> = peer_public_key.iter().cloned().collect();
+            for key in raw.peer_public_keys.iter().flat_map(|x| x.iter()) {
+                peer_public_keys.push(parse_public_key("peer_public_keys", key)?);
+            }
+            if peer_public_keys.is_empty() {
+                return Err(ConfigError::MissingFieldError("peer_public_key"));
+            }
+            PeerAuthenticator::Client(ClientAuthenticatorState {
+                peer_public_keys,
+                peer_ad_policy: parse_policy("peer_additional_data", &raw.peer_additional_data)?,
+                matched_key: None,
+            })
+        },
+        "server" => {
+            if raw.clients.is_some() {
+                return Err(ConfigError::InvalidFieldError("clients", "only providers accept clients".to_string()));
+            }
+            let (mix_map, mix_ad_policy) = parse_allowlist("mixes", &raw.mixes)?;
+            PeerAuthenticator::Server(ServerAuthenticatorState {
+                mix_map,
+                mix_ad_policy,
+            })
+        },
+        "provider" => {
+            let (mix_map, mix_ad_policy) = parse_allowlist("mixes", &raw.mixes)?;
+            let (client_map, client_ad_policy) = parse_allowlist("clients", &raw.clients)?;
+            PeerAuthenticator::Provider(ProviderAuthenticatorState {
+                mix_map,
+                client_map,
+                mix_ad_policy,
+                client_ad_policy,
+                from_client: false,
+                from_mix: false,
+            })
+        },
+        x => return Err(ConfigError::InvalidFieldError("role", format!("unknown role \"{}\", expected client, server or provider", x))),
+    };
+    if raw.peer_public_keys.is_some() && raw.role != "client" {
+        return Err(ConfigError::InvalidFieldError("peer_public_keys", "only used by clients".to_string()));
+    }
+    if raw.peer_additional_data.is_some() && raw.role != "client" {
+        return Err(ConfigError::InvalidFieldError("peer_additional_data", "only used by clients".to_string()));
+    }
+
+    let protocol_versions = raw.protocol_versions.unwrap_or_else(|| SUPPORTED_PROTOCOL_VERSIONS.to_vec());
+    if protocol_versions.is_empty() {
+        return Err(ConfigError::InvalidFieldError("protocol_versions", "at least one version is required".to_string()));
+    }
+    if let Some(version) = protocol_versions.iter().find(|x| **x > MAX_PROTOCOL_VERSION) {
+        return Err(ConfigError::InvalidFieldError("protocol_versions", format!("version {} is greater than {}", version, MAX_PROTOCOL_VERSION)));
+    }
+    let additional_data = raw.additional_data.map(|x| x.into_bytes()).unwrap_or_default();
+    if additional_data.len() > MAX_ADDITIONAL_DATA_SIZE {
+        return Err(ConfigError::InvalidFieldError("additional_data", format!("{} bytes is longer than the maximum of {}", additional_data.len(), MAX_ADDITIONAL_DATA_SIZE)));
+    }
+    if let Some(ref replay) = raw.replay {
+        if replay.capacity == 0 {
+            return Err(ConfigError::InvalidFieldError("replay", "capacity must be at least one".to_string()));
+        }
+    }
+
+    let mut rate_limits = RateLimits::default();
+    if let Some(ref raw_limits) = raw.rate_limits {
+        rate_limits.client = parse_rate_limit(&raw_limits.client)?;
+        rate_limits.mix = parse_rate_limit(&raw_limits.mix)?;
+    }
+
+    let mut admission = AdmissionConfig::default();
+    if let Some(ref x) = raw.admission {
+        if let Some(n) = x.max_concurrent_handshakes {
+            admission.max_concurrent_handshakes = n;
+        }
+        if let Some(ref bucket) = x.per_address {
+            admission.per_address = parse_bucket("admission", bucket)?;
+        }
+        if let Some(n) = x.max_authentication_failures {
+            admission.max_authentication_failures = n;
+        }
+        if let Some(secs) = x.ban_duration {
+            admission.ban_duration = Duration::from_secs(secs);
+        }
+        if let Some(secs) = x.handshake_timeout {
+            admission.handshake_timeout = Some(Duration::from_secs(secs));
+        }
+    }
+
+    let session = SessionConfig {
+        authenticator,
+        authentication_key: load_private_key(base_dir.join(&raw.private_key_file))?,
+        peer_public_key,
+        additional_data,
+        replay_cache: raw.replay.map(|x| ReplayCache::new(x.capacity, x.window)),
+        protocol_versions,
+        noise_suite: NoiseSuite::default(),
+        psk: None,
+        clock: None,
+        rng: None,
+        rate_limits,
+        metrics: None,
+    };
+    Ok(LinkConfig {
+        session,
+        admission,
+    })
+}
+
+fn parse_public_key(field: &'static str, text: &str) -> Result<PublicKey, ConfigError> {
+    parse_key(text).map_err(|_| {
+        ConfigError::InvalidFieldError(field, format!("\"{}\" is not a hex or base64 encoded public key", text))
+    })
+}
+
+fn parse_policy(field: &'static str, policy: &Option<String>) -> Result<AdditionalDataPolicy, ConfigError> {
+    match policy.as_ref().map(|x| x.as_str()) {
+        None | Some("any") => Ok(AdditionalDataPolicy::Any),
+        Some("username") => Ok(AdditionalDataPolicy::Username),
+        Some("node_identity") => Ok(AdditionalDataPolicy::NodeIdentity),
+        Some(x) => Err(ConfigError::InvalidFieldError(field, format!("unknown policy \"{}\", expected any, username or node_identity", x))),
+    }
+}
+
+fn parse_allowlist(field: &'static str, allowlist: &Option<RawAllowlist>) -> Result<(Allowlist, AdditionalDataPolicy), ConfigError> {
+    let map = Allowlist::new();
+    let allowlist = match *allowlist {
+        Some(ref x) => x,
+        None => return Ok((map, AdditionalDataPolicy::Any)),
+    };
+    for key in &allowlist.keys {
+        map.insert(parse_public_key(field, key)?);
+    }
+    map.set_terminate_revoked(allowlist.terminate_revoked.unwrap_or(false));
+    Ok((map, parse_policy(field, &allowlist.additional_data)?))
+}
+
+fn parse_bucket(field: &'static str, bucket: &RawBucket) -> Result<TokenBucketConfig, ConfigError> {
+    if bucket.rate == 0 || bucket.burst == 0 {
+        return Err(ConfigError::InvalidFieldError(field, "rate and burst must be at least one".to_string()));
+    }
+    Ok(TokenBucketConfig {
+        rate: bucket.rate,
+        burst: bucket.burst,
+    })
+}
+
+fn parse_rate_limit(limit: &Option<RawRateLimit>) -> Result<Option<RateLimitConfig>, ConfigError> {
+    let limit = match *limit {
+        Some(ref x) => x,
+        None => return Ok(None),
+    };
+    let policy = match limit.policy.as_str() {
+        "delay" => RateLimitPolicy::Delay,
+        "drop" => RateLimitPolicy::Drop,
+        "disconnect" => RateLimitPolicy::Disconnect,
+        x => return Err(ConfigError::InvalidFieldError("rate_limits", format!("unknown policy \"{}\", expected delay, drop or disconnect", x))),
+    };
+    Ok(Some(RateLimitConfig {
+        packets: parse_bucket("rate_limits", &limit.packets)?,
+        control: parse_bucket("rate_limits", &limit.control)?,
+        policy,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use std::env;
+    use std::process;
+    use self::rand::os::OsRng;
+    use ecdh_wrapper::PrivateKey;
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+    use super::*;
+    use super::super::keyfile::save_private_key;
+    use super::super::messages::AuthenticationKey;
+
+    #[test]
+    fn config_test() {
+        let mut rng = OsRng::new().expect("failure to create an OS RNG");
+        let dir = env::temp_dir().join(format!("mix_link-config-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = AuthenticationKey::from(PrivateKey::generate(&mut rng).unwrap());
+        save_private_key(dir.join("link.private"), &key).unwrap();
+        let client_key = PrivateKey::generate(&mut rng).unwrap().public_key();
+
+        let text = format!(r#"
+role = "provider"
+private_key_file = "link.private"
+additional_data = "provider1"
+
+[admission]
+handshake_timeout = 5
+
+[clients]
+keys = ["{}"]
+additional_data = "username"
+
+[rate_limits.client]
+packets = {{ rate = 100, burst = 200 }}
+control = {{ rate = 10, burst = 20 }}
+policy = "drop"
+"#, client_key.to_vec().to_base64(STANDARD));
+        fs::write(dir.join("link.toml"), &text).unwrap();
+        let config = load_config(dir.join("link.toml")).unwrap();
+        assert_eq!(config.session.authentication_key.public_key(), key.public_key());
+        assert_eq!(config.session.additional_data, b"provider1".to_vec());
+        assert_eq!(config.admission.handshake_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(config.session.rate_limits.client.unwrap().policy, RateLimitPolicy::Drop);
+        match config.session.authenticator {
+            PeerAuthenticator::Provider(ref state) => {
+                assert!(state.client_map.contains(&client_key));
+                assert_eq!(state.client_ad_policy, AdditionalDataPolicy::Username);
+            },
+            _ => panic!("expected a provider authenticator"),
+        }
+
+        let invalid = text.replace("\"username\"", "\"nickname\"");
+        match parse_config(&invalid, &dir) {
+            Err(ConfigError::InvalidFieldError("clients", _)) => {},
+            x => panic!("unexpected result {:?}", x.map(|_| ())),
+        }
+        match parse_config("role = \"client\"\nprivate_key_file = \"link.private\"\n", &dir) {
+            Err(ConfigError::MissingFieldError("peer_public_key")) => {},
+            x => panic!("unexpected result {:?}", x.map(|_| ())),
+        }
+
+        let invalid = [
+            ("protocol_versions", text.replace("additional_data = \"provider1\"", "additional_data = \"provider1\"\nprotocol_versions = [0, 8]")),
+            ("replay", text.replace("[admission]", "[replay]\ncapacity = 0\nwindow = 120\n\n[admission]")),
+            ("rate_limits", text.replace("rate = 10,", "rate = 0,")),
+            ("rate_limits", text.replace("burst = 200", "burst = 0")),
+            ("admission", text.replace("handshake_timeout = 5", "per_address = { rate = 0, burst = 10 }")),
+            ("additional_data", text.replace("\"provider1\"", &format!("\"{}\"", "x".repeat(256)))),
+        ];
+        for &(field, ref invalid) in invalid.iter() {
+            match parse_config(invalid, &dir) {
+                Err(ConfigError::InvalidFieldError(x, _)) if x == field => {},
+                x => panic!("unexpected result for {}: {:?}", field, x.map(|_| ())),
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -14,14 +14,20 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-pub const NOISE_PARAMS: & str = "Noise_XX_25519_ChaChaPoly_BLAKE2b";
+pub const PROTOCOL_VERSION_0: u8 = 0;
+pub const MAX_PROTOCOL_VERSION: u8 = 7;
+pub const SUPPORTED_PROTOCOL_VERSIONS: [u8; 1] = [PROTOCOL_VERSION_0];
 pub const PROLOGUE: [u8;1] = [0u8;1];
 pub const PROLOGUE_SIZE: usize = 1;
 pub const NOISE_MESSAGE_MAX_SIZE: usize = 65535;
 pub const KEY_SIZE: usize = 32;
+pub const PSK_SIZE: usize = 32;
+pub const MAX_EXPORT_SIZE: usize = 255 * 64;
 pub const MAC_SIZE: usize = 16;
 pub const MAX_ADDITIONAL_DATA_SIZE: usize = 255;
+pub const NODE_IDENTITY_SIZE: usize = 32;
 pub const AUTH_MESSAGE_SIZE: usize = 1 + 8 + MAX_ADDITIONAL_DATA_SIZE;
+pub const VERSIONED_AUTH_MESSAGE_SIZE: usize = AUTH_MESSAGE_SIZE + 1;
 pub const NOISE_HANDSHAKE_MESSAGE1_SIZE: usize = PROLOGUE_SIZE + KEY_SIZE;
 pub const NOISE_HANDSHAKE_MESSAGE2_SIZE: usize = 360;
 pub const NOISE_HANDSHAKE_MESSAGE3_SIZE: usize = 328;
@@ -0,0 +1,200 @@
+// epoch.rs - epoch scoped peer allowlists
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use ecdh_wrapper::PublicKey;
+
+use super::clock::Clock;
+
+
+/// An epoch schedule type, the defaults match the Katzenpost PKI.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct EpochConfig {
+    /// The unix time at which epoch 0 began.
+    pub genesis: u64,
+    /// The length of an epoch in seconds, must not be zero.
+    pub period: u64,
+    /// For this many seconds on either side of an epoch boundary the
+    /// keys of both adjacent epochs are accepted.
+    pub grace_period: u64,
+}
+
+impl Default for EpochConfig {
+    fn default() -> EpochConfig {
+        EpochConfig {
+            genesis: 1_496_275_200, // 2017-06-01 00:00:00 UTC
+            period: 3 * 60 * 60,
+            grace_period: 2 * 60,
+        }
+    }
+}
+
+impl EpochConfig {
+    /// Returns the epoch at the given unix time along with the number
+    /// of seconds elapsed since it began and remaining until it ends.
+    pub fn epoch_at(&self, now: u64) -> (u64, u64, u64) {
+        let since = now.saturating_sub(self.genesis);
+        let elapsed = since % self.period;
+        (since / self.period, elapsed, self.period - elapsed)
+    }
+}
+
+struct EpochAllowlistState {
+    epochs: BTreeMap<u64, HashSet<PublicKey>>,
+}
+
+/// EpochAllowlist holds the peer keys listed in each epoch's
+/// consensus. A key is accepted if it is listed for the current
+/// epoch, or for an adjacent epoch within the grace period of the
+/// boundary. Epochs are forgotten once they can no longer be valid.
+/// Clones share the same key sets.
+#[derive(Clone)]
+pub struct EpochAllowlist {
+    config: EpochConfig,
+    clock: Arc<dyn Clock>,
+    state: Arc<Mutex<EpochAllowlistState>>,
+}
+
+impl EpochAllowlist {
+    /// Panics if the epoch period is zero.
+    pub fn new(config: EpochConfig, clock: Arc<dyn Clock>) -> EpochAllowlist {
+        assert!(config.period > 0, "epoch period must not be zero");
+        EpochAllowlist {
+            config,
+            clock,
+            state: Arc::new(Mutex::new(EpochAllowlistState {
+                epochs: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn config(&self) -> &EpochConfig {
+        &self.config
+    }
+
+    pub fn current_epoch(&self) -> u64 {
+        self.config.epoch_at(self.clock.now()).0
+    }
+
+    /// Set the keys listed for an epoch, replacing any previous set.
+    /// Returns false if the epoch has already expired.
+    pub fn set_epoch_keys<I: IntoIterator<Item = PublicKey>>(&self, epoch: u64, keys: I) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if epoch < self.expire(&mut state) {
+            return false
+        }
+        state.epochs.insert(epoch, keys.into_iter().collect());
+        true
+    }
+
+    /// Returns the epochs whose keys are currently held.
+    pub fn epochs(&self) -> Vec<u64> {
+        let mut state = self.state.lock().unwrap();
+        self.expire(&mut state);
+        state.epochs.keys().cloned().collect()
+    }
+
+    pub fn is_valid(&self, key: &PublicKey) -> bool {
+        let (epoch, elapsed, remaining) = self.config.epoch_at(self.clock.now());
+        let mut state = self.state.lock().unwrap();
+        self.expire(&mut state);
+        let mut valid = vec![epoch];
+        if elapsed < self.config.grace_period && epoch > 0 {
+            valid.push(epoch - 1);
+        }
+        if remaining <= self.config.grace_period {
+            valid.push(epoch + 1);
+        }
+        valid.iter().any(|x| state.epochs.get(x).map_or(false, |keys| keys.contains(key)))
+    }
+
+    /// Forget the epochs which can no longer be valid, returning the
+    /// oldest epoch still kept.
+    fn expire(&self, state: &mut EpochAllowlistState) -> u64 {
+        let (epoch, elapsed, _) = self.config.epoch_at(self.clock.now());
+        let oldest = if elapsed < self.config.grace_period {
+            epoch.saturating_sub(1)
+        } else {
+            epoch
+        };
+        state.epochs = state.epochs.split_off(&oldest);
+        oldest
+    }
+}
+
+impl PartialEq for EpochAllowlist {
+    fn eq(&self, other: &EpochAllowlist) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl fmt::Debug for EpochAllowlist {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EpochAllowlist")
+            .field("config", &self.config)
+            .field("epochs", &self.state.lock().unwrap().epochs.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use self::rand::os::OsRng;
+    use ecdh_wrapper::PrivateKey;
+
+    use super::*;
+    use super::super::clock::ManualClock;
+
+    #[test]
+    fn epoch_allowlist_test() {
+        let mut rng = OsRng::new().unwrap();
+        let alice = PrivateKey::generate(&mut rng).unwrap().public_key();
+        let bob = PrivateKey::generate(&mut rng).unwrap().public_key();
+        let config = EpochConfig {
+            genesis: 1000,
+            period: 100,
+            grace_period: 10,
+        };
+        // The middle of epoch 5.
+        let clock = ManualClock::new(1550);
+        let allowlist = EpochAllowlist::new(config, Arc::new(clock.clone()));
+        assert_eq!(allowlist.current_epoch(), 5);
+        assert!(!allowlist.set_epoch_keys(4, vec![bob.clone()]));
+        assert!(allowlist.set_epoch_keys(5, vec![alice.clone()]));
+        assert!(allowlist.set_epoch_keys(6, vec![bob.clone()]));
+        assert!(allowlist.is_valid(&alice));
+        assert!(!allowlist.is_valid(&bob));
+
+        // Both epochs are valid around the boundary.
+        clock.set(1595);
+        assert!(allowlist.is_valid(&alice));
+        assert!(allowlist.is_valid(&bob));
+        clock.set(1605);
+        assert!(allowlist.is_valid(&alice));
+        assert!(allowlist.is_valid(&bob));
+
+        // Epoch 5 expires after the grace period.
+        clock.set(1615);
+        assert!(!allowlist.is_valid(&alice));
+        assert!(allowlist.is_valid(&bob));
+        assert_eq!(allowlist.epochs(), vec![6]);
+    }
+}
@@ -22,10 +22,14 @@ use std::io::{self};
 
 use snow::SnowError;
 
+use super::topology::Layer;
+
 
 #[derive(Debug)]
 pub enum AuthenticationError {
     InvalidSize,
+    InvalidUsername,
+    InvalidNodeIdentity,
 }
 
 impl fmt::Display for AuthenticationError {
@@ -33,6 +37,8 @@ impl fmt::Display for AuthenticationError {
         use self::AuthenticationError::*;
         match self {
             InvalidSize => write!(f, "Invalid authentication message size."),
+            InvalidUsername => write!(f, "Additional data is not a valid username."),
+            InvalidNodeIdentity => write!(f, "Additional data is not a valid node identity."),
         }
     }
 }
@@ -47,6 +53,8 @@ impl Error for AuthenticationError {
         use self::AuthenticationError::*;
         match self {
             InvalidSize => None,
+            InvalidUsername => None,
+            InvalidNodeIdentity => None,
         }
     }
 }
@@ -89,6 +97,214 @@ impl From<SnowError> for RekeyError {
 
 
 
+#[derive(Debug)]
+pub enum ExportError {
+    HandshakeIncompleteError,
+    InvalidLengthError,
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ExportError::*;
+        match self {
+            HandshakeIncompleteError => write!(f, "Handshake has not completed."),
+            InvalidLengthError => write!(f, "Invalid exported keying material length."),
+        }
+    }
+}
+
+impl Error for ExportError {
+    fn description(&self) -> &str {
+        "I'm an export error."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::ExportError::*;
+        match self {
+            HandshakeIncompleteError => None,
+            InvalidLengthError => None,
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AdmissionError {
+    TooManyHandshakesError,
+    RateLimitedError,
+    BannedError,
+}
+
+impl fmt::Display for AdmissionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AdmissionError::*;
+        match self {
+            TooManyHandshakesError => write!(f, "Too many concurrent handshakes."),
+            RateLimitedError => write!(f, "Too many handshakes from this address."),
+            BannedError => write!(f, "Address is banned."),
+        }
+    }
+}
+
+impl AdmissionError {
+    /// Returns the name of the error variant, used as a metrics label.
+    pub fn name(&self) -> &'static str {
+        use self::AdmissionError::*;
+        match *self {
+            TooManyHandshakesError => "TooManyHandshakesError",
+            RateLimitedError => "RateLimitedError",
+            BannedError => "BannedError",
+        }
+    }
+}
+
+impl Error for AdmissionError {
+    fn description(&self) -> &str {
+        "I'm an admission error."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::AdmissionError::*;
+        match self {
+            TooManyHandshakesError => None,
+            RateLimitedError => None,
+            BannedError => None,
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TopologyError {
+    UnknownPeerError,
+    /// The peer's layer and the layer expected on this link.
+    WrongLayerError(Layer, Layer),
+}
+
+impl fmt::Display for TopologyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::TopologyError::*;
+        match self {
+            UnknownPeerError => write!(f, "Peer is not in the topology."),
+            WrongLayerError(peer, expected) => write!(f, "Peer is in {} but only {} may be linked to.", peer, expected),
+        }
+    }
+}
+
+impl Error for TopologyError {
+    fn description(&self) -> &str {
+        "I'm a topology error."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::TopologyError::*;
+        match self {
+            UnknownPeerError => None,
+            WrongLayerError(_, _) => None,
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum KeyFileError {
+    IOError(io::Error),
+    InvalidFormatError,
+    WrongKeyTypeError,
+    ChecksumError,
+    InvalidKeyError,
+    InsecurePermissionsError,
+}
+
+impl fmt::Display for KeyFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::KeyFileError::*;
+        match self {
+            IOError(ref x) => x.fmt(f),
+            InvalidFormatError => write!(f, "Invalid key file format."),
+            WrongKeyTypeError => write!(f, "Key file holds a different type of key."),
+            ChecksumError => write!(f, "Key file checksum mismatch."),
+            InvalidKeyError => write!(f, "Key file holds an invalid key."),
+            InsecurePermissionsError => write!(f, "Private key file is readable by other users."),
+        }
+    }
+}
+
+impl Error for KeyFileError {
+    fn description(&self) -> &str {
+        "I'm a key file error."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::KeyFileError::*;
+        match self {
+            IOError(_) => None,
+            InvalidFormatError => None,
+            WrongKeyTypeError => None,
+            ChecksumError => None,
+            InvalidKeyError => None,
+            InsecurePermissionsError => None,
+        }
+    }
+}
+
+impl From<io::Error> for KeyFileError {
+    fn from(error: io::Error) -> Self {
+        KeyFileError::IOError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IOError(io::Error),
+    ParseError(String),
+    KeyFileError(KeyFileError),
+    MissingFieldError(&'static str),
+    InvalidFieldError(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ConfigError::*;
+        match self {
+            IOError(ref x) => x.fmt(f),
+            ParseError(ref x) => write!(f, "Failed to parse configuration: {}", x),
+            KeyFileError(ref x) => x.fmt(f),
+            MissingFieldError(field) => write!(f, "Missing configuration field `{}`.", field),
+            InvalidFieldError(field, ref reason) => write!(f, "Invalid configuration field `{}`: {}", field, reason),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        "I'm a configuration error."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::ConfigError::*;
+        match self {
+            IOError(_) => None,
+            ParseError(_) => None,
+            KeyFileError(x) => x.cause(),
+            MissingFieldError(_) => None,
+            InvalidFieldError(_, _) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        ConfigError::IOError(error)
+    }
+}
+
+impl From<KeyFileError> for ConfigError {
+    fn from(error: KeyFileError) -> Self {
+        ConfigError::KeyFileError(error)
+    }
+}
+
 #[derive(Debug)]
 pub enum CommandError {
     InvalidNoiseSpecError,
@@ -105,6 +321,7 @@ pub enum CommandError {
     RetreiveMessageDecodeError,
     MessageDecodeError,
     InvalidMessageType,
+    InvalidPaddingError,
     InvalidStateError,
 }
 
@@ -125,6 +342,7 @@ impl fmt::Display for CommandError {
             RetreiveMessageDecodeError => write!(f, "Failed to decode a RetreiveMessage command."),
             MessageDecodeError => write!(f, "Failed to decode a Message command."),
             InvalidMessageType => write!(f, "Failed to decode a Message command with invalid type."),
+            InvalidPaddingError => write!(f, "Command padding is not all zeros."),
             InvalidStateError => write!(f, "Encountered invalid state transition."),
         }
     }
@@ -151,6 +369,7 @@ impl Error for CommandError {
             RetreiveMessageDecodeError => None,
             MessageDecodeError => None,
             InvalidMessageType => None,
+            InvalidPaddingError => None,
             InvalidStateError => None,
         }
     }
@@ -170,6 +389,9 @@ pub enum ClientHandshakeError {
     FailedToGetRemoteStatic,
     FailedToDecodeRemoteStatic,
     InvalidStateError,
+    VersionMismatchError,
+    DecryptError,
+    TopologyError(TopologyError),
     SnowError(SnowError),
 }
 
@@ -189,11 +411,39 @@ impl fmt::Display for ClientHandshakeError {
             FailedToGetRemoteStatic => write!(f, "Failed to get remote static key."),
             FailedToDecodeRemoteStatic => write!(f, "Failed to decode remote static key."),
             InvalidStateError => write!(f, "Invalid state transition."),
+            VersionMismatchError => write!(f, "Peer selected a protocol version we did not offer."),
+            DecryptError => write!(f, "Failed to decrypt second noise handshake message, the peers may use a different pre-shared key or noise suite or the message was corrupted."),
+            TopologyError(x) => x.fmt(f),
             SnowError(x) => x.fmt(f),
         }
     }
 }
 
+impl ClientHandshakeError {
+    /// Returns the name of the error variant.
+    pub fn name(&self) -> &'static str {
+        use self::ClientHandshakeError::*;
+        match *self {
+            InvalidNoiseSpecError => "InvalidNoiseSpecError",
+            NoPeerKeyError => "NoPeerKeyError",
+            SessionCreateError => "SessionCreateError",
+            Noise1WriteError => "Noise1WriteError",
+            Noise2ReadError => "Noise2ReadError",
+            Noise3WriteError => "Noise3WriteError",
+            SentHandshake1InvalidState => "SentHandshake1InvalidState",
+            InitiateDataTransferError => "InitiateDataTransferError",
+            AuthenticationError => "AuthenticationError",
+            FailedToGetRemoteStatic => "FailedToGetRemoteStatic",
+            FailedToDecodeRemoteStatic => "FailedToDecodeRemoteStatic",
+            InvalidStateError => "InvalidStateError",
+            VersionMismatchError => "VersionMismatchError",
+            DecryptError => "DecryptError",
+            TopologyError(_) => "TopologyError",
+            SnowError(_) => "SnowError",
+        }
+    }
+}
+
 impl Error for ClientHandshakeError {
     fn description(&self) -> &str {
         "I'm a client handshake error."
@@ -214,6 +464,9 @@ impl Error for ClientHandshakeError {
             FailedToGetRemoteStatic => None,
             FailedToDecodeRemoteStatic => None,
             InvalidStateError => None,
+            VersionMismatchError => None,
+            DecryptError => None,
+            TopologyError(ref x) => x.cause(),
             SnowError(_) => None,
         }
     }
@@ -229,6 +482,8 @@ impl From<SnowError> for ClientHandshakeError {
 #[derive(Debug)]
 pub enum ServerHandshakeError {
     PrologueMismatchError,
+    NoCommonVersionError,
+    VersionMismatchError,
     InvalidNoiseSpecError,
     NoPeerKeyError,
     SessionCreateError,
@@ -241,7 +496,12 @@ pub enum ServerHandshakeError {
     FailedToGetRemoteStatic,
     FailedToDecodeRemoteStatic,
     InvalidStateError,
+    ReplayError,
+    ReplayCacheFullError,
+    StaleTimestampError,
+    DecryptError,
     SnowError(SnowError),
+    TopologyError(TopologyError),
 }
 
 impl fmt::Display for ServerHandshakeError {
@@ -249,6 +509,8 @@ impl fmt::Display for ServerHandshakeError {
         use self::ServerHandshakeError::*;
         match self {
             PrologueMismatchError => write!(f, "Prologue mismatch error."),
+            NoCommonVersionError => write!(f, "No protocol version in common with the peer."),
+            VersionMismatchError => write!(f, "Peer did not confirm the negotiated protocol version."),
             InvalidNoiseSpecError => write!(f, "Invalid noise protocol string."),
             NoPeerKeyError => write!(f, "No peer key was supplied."),
             SessionCreateError => write!(f, "Session creation failure."),
@@ -261,7 +523,42 @@ impl fmt::Display for ServerHandshakeError {
             FailedToGetRemoteStatic => write!(f, "Failed to get remote static key."),
             FailedToDecodeRemoteStatic => write!(f, "Failed to decode remote static key."),
             InvalidStateError => write!(f, "Invalid state transition."),
+            ReplayError => write!(f, "Replayed handshake message received."),
+            ReplayCacheFullError => write!(f, "Replay cache is full of unexpired handshakes."),
+            StaleTimestampError => write!(f, "Handshake timestamp is outside of the allowed clock skew."),
+            DecryptError => write!(f, "Failed to decrypt third noise handshake message, the peers may use a different pre-shared key or noise suite or the message was corrupted."),
             SnowError(x) => x.fmt(f),
+            TopologyError(x) => x.fmt(f),
+        }
+    }
+}
+
+impl ServerHandshakeError {
+    /// Returns the name of the error variant.
+    pub fn name(&self) -> &'static str {
+        use self::ServerHandshakeError::*;
+        match *self {
+            PrologueMismatchError => "PrologueMismatchError",
+            NoCommonVersionError => "NoCommonVersionError",
+            VersionMismatchError => "VersionMismatchError",
+            InvalidNoiseSpecError => "InvalidNoiseSpecError",
+            NoPeerKeyError => "NoPeerKeyError",
+            SessionCreateError => "SessionCreateError",
+            Noise1ReadError => "Noise1ReadError",
+            Noise2WriteError => "Noise2WriteError",
+            Noise3ReadError => "Noise3ReadError",
+            SentHandshake1InvalidState => "SentHandshake1InvalidState",
+            InitiateDataTransferError => "InitiateDataTransferError",
+            AuthenticationError => "AuthenticationError",
+            FailedToGetRemoteStatic => "FailedToGetRemoteStatic",
+            FailedToDecodeRemoteStatic => "FailedToDecodeRemoteStatic",
+            InvalidStateError => "InvalidStateError",
+            ReplayError => "ReplayError",
+            ReplayCacheFullError => "ReplayCacheFullError",
+            StaleTimestampError => "StaleTimestampError",
+            DecryptError => "DecryptError",
+            SnowError(_) => "SnowError",
+            TopologyError(_) => "TopologyError",
         }
     }
 }
@@ -275,6 +572,8 @@ impl Error for ServerHandshakeError {
         use self::ServerHandshakeError::*;
         match *self {
             PrologueMismatchError => None,
+            NoCommonVersionError => None,
+            VersionMismatchError => None,
             InvalidNoiseSpecError => None,
             NoPeerKeyError => None,
             SessionCreateError => None,
@@ -287,7 +586,12 @@ impl Error for ServerHandshakeError {
             FailedToGetRemoteStatic => None,
             FailedToDecodeRemoteStatic => None,
             InvalidStateError => None,
+            ReplayError => None,
+            ReplayCacheFullError => None,
+            StaleTimestampError => None,
+            DecryptError => None,
             SnowError(_) => None,
+            TopologyError(ref x) => x.cause(),
         }
     }
 }
@@ -302,6 +606,8 @@ impl From<SnowError> for ServerHandshakeError {
 #[derive(Debug)]
 pub enum HandshakeError {
     InvalidNoiseSpecError,
+    InvalidProtocolVersionError,
+    InvalidRateLimitError,
     NoPeerKeyError,
     SessionCreateError,
     ClientHandshakeError(ClientHandshakeError),
@@ -312,6 +618,7 @@ pub enum HandshakeError {
     SnowError(snow::SnowError),
     ReceiveMessageError(ReceiveMessageError),
     SendMessageError(SendMessageError),
+    PeerRevokedError,
 }
 
 impl fmt::Display for HandshakeError {
@@ -319,13 +626,43 @@ impl fmt::Display for HandshakeError {
         use self::HandshakeError::*;
         match self {
             InvalidNoiseSpecError => write!(f, "Invalid noise protocol string."),
+            InvalidProtocolVersionError => write!(f, "Invalid set of supported protocol versions."),
+            InvalidRateLimitError => write!(f, "Invalid rate limit, rates and bursts must be at least one."),
             NoPeerKeyError => write!(f, "No peer key was supplied."),
             SessionCreateError => write!(f, "Session creation failure."),
             ClientHandshakeError(x) => x.fmt(f),
             ServerHandshakeError(x) => x.fmt(f),
             InvalidHandshakeFinalize => write!(f, "Invalid command received from handshake finalization."),
             InvalidStateError => write!(f, "Impossible error like this should never happen."),
-            _ => write!(f, "Impossible error like this should never happen."),
+            IOError(ref x) => x.fmt(f),
+            SnowError(ref x) => x.fmt(f),
+            ReceiveMessageError(ref x) => x.fmt(f),
+            SendMessageError(ref x) => x.fmt(f),
+            PeerRevokedError => write!(f, "Peer was removed from the allowlist during the handshake."),
+        }
+    }
+}
+
+impl HandshakeError {
+    /// Returns the name of the error variant, or of the inner variant
+    /// of client and server handshake errors. Used as a metrics label.
+    pub fn name(&self) -> &'static str {
+        use self::HandshakeError::*;
+        match *self {
+            InvalidNoiseSpecError => "InvalidNoiseSpecError",
+            InvalidProtocolVersionError => "InvalidProtocolVersionError",
+            InvalidRateLimitError => "InvalidRateLimitError",
+            NoPeerKeyError => "NoPeerKeyError",
+            SessionCreateError => "SessionCreateError",
+            ClientHandshakeError(ref x) => x.name(),
+            ServerHandshakeError(ref x) => x.name(),
+            InvalidStateError => "InvalidStateError",
+            InvalidHandshakeFinalize => "InvalidHandshakeFinalize",
+            IOError(_) => "IOError",
+            SnowError(_) => "SnowError",
+            ReceiveMessageError(_) => "ReceiveMessageError",
+            SendMessageError(_) => "SendMessageError",
+            PeerRevokedError => "PeerRevokedError",
         }
     }
 }
@@ -339,6 +676,8 @@ impl Error for HandshakeError {
         use self::HandshakeError::*;
         match self {
             InvalidNoiseSpecError => None,
+            InvalidProtocolVersionError => None,
+            InvalidRateLimitError => None,
             NoPeerKeyError => None,
             SessionCreateError => None,
             ClientHandshakeError(x) => x.cause(),
@@ -349,6 +688,7 @@ impl Error for HandshakeError {
             ReceiveMessageError(x) => x.cause(),
             SendMessageError(x) => x.cause(),
             InvalidHandshakeFinalize => None,
+            PeerRevokedError => None,
         }
     }
 }
@@ -396,6 +736,10 @@ pub enum SendMessageError {
     EncryptFail,
     RekeyError(RekeyError),
     IOError(io::Error),
+    QueueFull,
+    QueueClosed,
+    InvalidQueueCapacity,
+    WriterPanicked,
 }
 
 impl fmt::Display for SendMessageError {
@@ -406,6 +750,10 @@ impl fmt::Display for SendMessageError {
             EncryptFail => write!(f, "Failure to encrypt."),
             IOError(ref x) => x.fmt(f),
             RekeyError(x) => x.fmt(f),
+            QueueFull => write!(f, "Send queue is full."),
+            QueueClosed => write!(f, "Send queue is closed."),
+            InvalidQueueCapacity => write!(f, "Send queue capacity must be at least one."),
+            WriterPanicked => write!(f, "Send queue writer thread panicked."),
         }
     }
 }
@@ -422,6 +770,10 @@ impl Error for SendMessageError {
             EncryptFail => None,
             IOError(_) => None,
             RekeyError(x) => x.cause(),
+            QueueFull => None,
+            QueueClosed => None,
+            InvalidQueueCapacity => None,
+            WriterPanicked => None,
         }
     }
 }
@@ -446,6 +798,7 @@ pub enum ReceiveMessageError {
     CommandError(CommandError),
     IOError(io::Error),
     RekeyError(RekeyError),
+    RateLimitExceeded,
 }
 
 impl fmt::Display for ReceiveMessageError {
@@ -457,6 +810,7 @@ impl fmt::Display for ReceiveMessageError {
             CommandError(x) => x.fmt(f),
             IOError(ref x) => x.fmt(f),
             RekeyError(x) => x.fmt(f),
+            RateLimitExceeded => write!(f, "Peer exceeded its rate limit."),
         }
     }
 }
@@ -474,6 +828,7 @@ impl Error for ReceiveMessageError {
             CommandError(_) => None,
             IOError(_) => None,
             RekeyError(x) => x.cause(),
+            RateLimitExceeded => None,
         }
     }
 }
@@ -0,0 +1,241 @@
+// keyfile.rs - armored link key files
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Link keys are stored in a PEM style armor, e.g.
+//!
+//! ```text
+//! -----BEGIN MIX_LINK PUBLIC KEY-----
+//! ZV67wsfmEY6ryLt+RsBCNBc/pOXBw6T2rGnZ0ZvHEi4=
+//! =DyU8eg==
+//! -----END MIX_LINK PUBLIC KEY-----
+//! ```
+//!
+//! The key is base64 encoded, followed by a checksum line holding the
+//! first four bytes of the BLAKE2b hash of the label and the key.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use blake2_rfc::blake2b::Blake2b;
+use ecdh_wrapper::{PrivateKey, PublicKey, KEY_SIZE};
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::hex::FromHex;
+use zeroize::Zeroize;
+
+use super::errors::KeyFileError;
+use super::messages::AuthenticationKey;
+
+
+pub const PRIVATE_KEY_LABEL: &str = "MIX_LINK PRIVATE KEY";
+pub const PUBLIC_KEY_LABEL: &str = "MIX_LINK PUBLIC KEY";
+
+const CHECKSUM_SIZE: usize = 4;
+
+fn checksum(label: &str, key: &[u8]) -> Vec<u8> {
+    let mut hash = Blake2b::new(CHECKSUM_SIZE);
+    hash.update(label.as_bytes());
+    hash.update(key);
+    hash.finalize().as_bytes().to_vec()
+}
+
+fn encode(label: &str, key: &[u8]) -> String {
+    format!("-----BEGIN {}-----\n{}\n={}\n-----END {}-----\n",
+            label, key.to_base64(STANDARD), checksum(label, key).to_base64(STANDARD), label)
+}
+
+fn decode(label: &str, armored: &str) -> Result<Vec<u8>, KeyFileError> {
+    let lines: Vec<&str> = armored.lines().map(|x| x.trim()).filter(|x| !x.is_empty()).collect();
+    if lines.len() != 4 {
+        return Err(KeyFileError::InvalidFormatError);
+    }
+    let found_label = match (parse_boundary(lines[0], "BEGIN"), parse_boundary(lines[3], "END")) {
+        (Some(begin), Some(end)) if begin == end => begin,
+        _ => return Err(KeyFileError::InvalidFormatError),
+    };
+    if found_label != label {
+        return Err(KeyFileError::WrongKeyTypeError);
+    }
+    if !lines[2].starts_with('=') {
+        return Err(KeyFileError::InvalidFormatError);
+    }
+    let mut key = lines[1].from_base64().map_err(|_| KeyFileError::InvalidFormatError)?;
+    let sum = lines[2][1..].from_base64().map_err(|_| KeyFileError::InvalidFormatError)?;
+    if sum != checksum(label, &key) {
+        key.zeroize();
+        return Err(KeyFileError::ChecksumError);
+    }
+    if key.len() != KEY_SIZE {
+        key.zeroize();
+        return Err(KeyFileError::InvalidKeyError);
+    }
+    Ok(key)
+}
+
+fn parse_boundary<'a>(line: &'a str, kind: &str) -> Option<&'a str> {
+    let prefix = format!("-----{} ", kind);
+    if line.starts_with(&prefix) && line.ends_with("-----") && line.len() > prefix.len() + 5 {
+        return Some(&line[prefix.len()..line.len() - 5])
+    }
+    None
+}
+
+pub fn encode_private_key(key: &AuthenticationKey) -> String {
+    key.with_bytes(|raw| encode(PRIVATE_KEY_LABEL, raw))
+}
+
+pub fn decode_private_key(armored: &str) -> Result<AuthenticationKey, KeyFileError> {
+    let mut raw = decode(PRIVATE_KEY_LABEL, armored)?;
+    let mut key = PrivateKey::default();
+    let result = key.from_bytes(&raw);
+    raw.zeroize();
+    let key = AuthenticationKey::from(key);
+    result.map_err(|_| KeyFileError::InvalidKeyError)?;
+    Ok(key)
+}
+
+pub fn encode_public_key(key: &PublicKey) -> String {
+    encode(PUBLIC_KEY_LABEL, &key.to_vec())
+}
+
+pub fn decode_public_key(armored: &str) -> Result<PublicKey, KeyFileError> {
+    let raw = decode(PUBLIC_KEY_LABEL, armored)?;
+    let mut key = PublicKey::default();
+    key.from_bytes(&raw).map_err(|_| KeyFileError::InvalidKeyError)?;
+    Ok(key)
+}
+
+/// Parse a bare public key given in hex or base64, as printed by
+/// `mix_link-keygen`.
+pub fn parse_public_key(text: &str) -> Result<PublicKey, KeyFileError> {
+    let text = text.trim();
+    let raw = if text.len() == KEY_SIZE * 2 {
+        text.from_hex().map_err(|_| KeyFileError::InvalidFormatError)?
+    } else {
+        text.from_base64().map_err(|_| KeyFileError::InvalidFormatError)?
+    };
+    let mut key = PublicKey::default();
+    key.from_bytes(&raw).map_err(|_| KeyFileError::InvalidKeyError)?;
+    Ok(key)
+}
+
+/// Write a private key to a new file only its owner may read.
+/// Existing files are never overwritten.
+pub fn save_private_key<P: AsRef<Path>>(path: P, key: &AuthenticationKey) -> Result<(), KeyFileError> {
+    let mut armored = encode_private_key(key);
+    let result = write_new_file(path.as_ref(), armored.as_bytes(), 0o600);
+    armored.zeroize();
+    result
+}
+
+/// Read a private key file, refusing files other users can read.
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> Result<AuthenticationKey, KeyFileError> {
+    check_permissions(path.as_ref())?;
+    let mut armored = fs::read_to_string(path)?;
+    let result = decode_private_key(&armored);
+    armored.zeroize();
+    result
+}
+
+/// Write a public key to a new file. Existing files are never
+/// overwritten.
+pub fn save_public_key<P: AsRef<Path>>(path: P, key: &PublicKey) -> Result<(), KeyFileError> {
+    write_new_file(path.as_ref(), encode_public_key(key).as_bytes(), 0o644)
+}
+
+pub fn load_public_key<P: AsRef<Path>>(path: P) -> Result<PublicKey, KeyFileError> {
+    decode_public_key(&fs::read_to_string(path)?)
+}
+
+fn write_new_file(path: &Path, contents: &[u8], mode: u32) -> Result<(), KeyFileError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    set_mode(&mut options, mode);
+    options.open(path)?.write_all(contents)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(options: &mut OpenOptions, mode: u32) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(mode);
+}
+
+#[cfg(not(unix))]
+fn set_mode(_options: &mut OpenOptions, _mode: u32) {
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), KeyFileError> {
+    use std::os::unix::fs::PermissionsExt;
+    if fs::metadata(path)?.permissions().mode() & 0o004 != 0 {
+        return Err(KeyFileError::InsecurePermissionsError);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), KeyFileError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use std::env;
+    use std::process;
+    use self::rand::os::OsRng;
+    use super::*;
+
+    #[test]
+    fn key_file_test() {
+        let mut rng = OsRng::new().expect("failure to create an OS RNG");
+        let private_key = AuthenticationKey::from(PrivateKey::generate(&mut rng).unwrap());
+        let public_key = private_key.public_key();
+
+        let armored = encode_public_key(&public_key);
+        assert_eq!(decode_public_key(&armored).unwrap(), public_key);
+        match decode_private_key(&armored) {
+            Err(KeyFileError::WrongKeyTypeError) => {},
+            _ => panic!("expected a key type error"),
+        }
+        let mut corrupted: Vec<&str> = armored.lines().collect();
+        let flipped = if corrupted[1].starts_with('A') { corrupted[1].replacen('A', "B", 1) } else { format!("A{}", &corrupted[1][1..]) };
+        corrupted[1] = &flipped;
+        match decode_public_key(&corrupted.join("\n")) {
+            Err(KeyFileError::ChecksumError) => {},
+            _ => panic!("expected a checksum error"),
+        }
+
+        let dir = env::temp_dir().join(format!("mix_link-keyfile-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let private_path = dir.join("link.private");
+        save_private_key(&private_path, &private_key).unwrap();
+        assert!(save_private_key(&private_path, &private_key).is_err());
+        assert_eq!(load_private_key(&private_path).unwrap().public_key(), public_key);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&private_path, fs::Permissions::from_mode(0o644)).unwrap();
+            match load_private_key(&private_path) {
+                Err(KeyFileError::InsecurePermissionsError) => {},
+                _ => panic!("expected a permissions error"),
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
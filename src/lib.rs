@@ -19,12 +19,51 @@ extern crate ecdh_wrapper;
 extern crate byteorder;
 extern crate subtle;
 extern crate sphinxcrypto;
+extern crate blake2_rfc;
+extern crate zeroize;
+#[cfg(any(feature = "keyfile", feature = "serialization"))]
+extern crate rustc_serialize;
+#[cfg(feature = "logging")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "serialization")]
+extern crate serde;
+#[cfg(feature = "serialization")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "config")]
+extern crate toml;
+#[cfg(all(test, feature = "json"))]
+extern crate serde_json;
+
+#[macro_use]
+mod logging;
 
 pub mod errors;
+pub mod clock;
+pub mod rng;
 pub mod constants;
 pub mod commands;
 pub mod messages;
+pub mod replay;
+pub mod allowlist;
+pub mod epoch;
+pub mod topology;
+pub mod suite;
+mod cipherstate;
+pub mod transport;
 pub mod sync;
+pub mod queue;
+pub mod rate_limit;
+pub mod admission;
+pub mod listener;
+pub mod metrics;
+#[cfg(feature = "keyfile")]
+pub mod keyfile;
+#[cfg(feature = "serialization")]
+mod serialization;
+#[cfg(feature = "config")]
+pub mod config;
 
 
 #[cfg(test)]
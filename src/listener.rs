@@ -0,0 +1,180 @@
+// listener.rs - accepting inbound sessions
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use super::admission::{AdmissionControl, AdmissionConfig, HandshakePermit};
+use super::errors::{HandshakeError, ServerHandshakeError};
+use super::messages::SessionConfig;
+use super::sync::Session;
+
+
+/// Listener accepts inbound connections, applying admission control
+/// before any handshake work is done for them.
+pub struct Listener {
+    listener: TcpListener,
+    config: SessionConfig,
+    admission: AdmissionControl,
+}
+
+impl Listener {
+    pub fn bind<A: ToSocketAddrs>(address: A, config: SessionConfig, admission: AdmissionConfig) -> io::Result<Listener> {
+        Ok(Listener {
+            listener: TcpListener::bind(address)?,
+            config,
+            admission: AdmissionControl::new(admission),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns the admission control state, e.g. to ban an address.
+    pub fn admission(&self) -> &AdmissionControl {
+        &self.admission
+    }
+
+    /// Wait for a connection which passes admission control,
+    /// connections which do not are closed immediately.
+    pub fn accept(&self) -> io::Result<PendingSession> {
+        loop {
+            let (stream, address) = self.listener.accept()?;
+            let permit = match self.admission.admit(address.ip()) {
+                Ok(permit) => permit,
+                Err(e) => {
+                    log_debug!("rejected connection from {}: {}", address, e);
+                    if let Some(ref metrics) = self.config.metrics {
+                        metrics.connection_rejected(&e);
+                    }
+                    continue
+                },
+            };
+            return Ok(PendingSession {
+                stream,
+                address,
+                config: self.config.clone(),
+                permit,
+                timeout: self.admission.config().handshake_timeout,
+            })
+        }
+    }
+}
+
+/// PendingSession is an admitted connection whose handshake has not
+/// yet been run. It holds a handshake slot until the handshake ends.
+pub struct PendingSession {
+    stream: TcpStream,
+    address: SocketAddr,
+    config: SessionConfig,
+    permit: HandshakePermit,
+    timeout: Option<Duration>,
+}
+
+impl PendingSession {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Run the responder handshake. Authentication failures count
+    /// towards banning the peer's address.
+    pub fn handshake(self) -> Result<Session, HandshakeError> {
+        self.stream.set_read_timeout(self.timeout)?;
+        self.stream.set_write_timeout(self.timeout)?;
+        let result = handshake(self.config, self.stream.try_clone()?);
+        match result {
+            Ok(_) => self.permit.authenticated(),
+            Err(HandshakeError::ServerHandshakeError(ServerHandshakeError::AuthenticationError)) |
+            Err(HandshakeError::ServerHandshakeError(ServerHandshakeError::TopologyError(_))) => {
+                self.permit.authentication_failed()
+            },
+            Err(_) => {},
+        }
+        self.stream.set_read_timeout(None)?;
+        self.stream.set_write_timeout(None)?;
+        result
+    }
+}
+
+fn handshake(config: SessionConfig, stream: TcpStream) -> Result<Session, HandshakeError> {
+    let mut session = Session::new(config, false)?;
+    session.initialize(stream)?;
+    session = session.into_transport_mode()?;
+    session.finalize_handshake()?;
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::thread;
+    use self::rand::os::OsRng;
+    use ecdh_wrapper::PrivateKey;
+    use super::*;
+    use super::super::commands::Command;
+    use super::super::metrics::InMemoryMetrics;
+    use super::super::sync::tests::test_configs;
+
+    #[test]
+    fn listener_ban_test() {
+        let mut rng = OsRng::new().expect("failure to create an OS RNG");
+        let (known, mut server_config) = test_configs();
+        let mut stranger = known.clone();
+        stranger.authentication_key = PrivateKey::generate(&mut rng).unwrap().into();
+        let metrics = InMemoryMetrics::new();
+        server_config.metrics = Some(Arc::new(metrics.clone()));
+        let admission = AdmissionConfig {
+            max_authentication_failures: 1,
+            ..AdmissionConfig::default()
+        };
+        let listener = Listener::bind("127.0.0.1:0", server_config, admission).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // An unknown client is rejected and banned.
+        let client = thread::spawn(move|| {
+            let mut session = Session::new(stranger, true).unwrap();
+            let _ = session.initialize(TcpStream::connect(address).unwrap());
+        });
+        assert!(listener.accept().unwrap().handshake().is_err());
+        client.join().unwrap();
+        assert!(listener.admission().is_banned(address.ip()));
+        assert_eq!(listener.admission().handshakes(), 0);
+
+        // A banned address is refused before any handshake, once
+        // unbanned a known client gets through.
+        let admission = listener.admission().clone();
+        let client = thread::spawn(move|| {
+            let mut refused = TcpStream::connect(address).unwrap();
+            assert_eq!(refused.read(&mut [0u8; 1]).unwrap_or(0), 0);
+            admission.unban(address.ip());
+            let mut session = Session::new(known, true).unwrap();
+            session.initialize(TcpStream::connect(address).unwrap()).unwrap();
+            session = session.into_transport_mode().unwrap();
+            session.finalize_handshake().unwrap();
+            session.send_command(&Command::NoOp{}).unwrap();
+        });
+        let mut session = listener.accept().unwrap().handshake().unwrap();
+        assert_eq!(session.recv_command().unwrap(), Command::NoOp{});
+        client.join().unwrap();
+        assert_eq!(metrics.snapshot().connections_rejected.get("BannedError"), Some(&1));
+    }
+}
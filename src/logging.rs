@@ -0,0 +1,68 @@
+// logging.rs - optional logging through the log facade
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Events are only logged when the `logging` feature is enabled.
+//! Keys, payloads and other secrets must never be logged, peers are
+//! identified by the fingerprint of their public key.
+
+use std::fmt::Write;
+
+use blake2_rfc::blake2b::blake2b;
+use ecdh_wrapper::PublicKey;
+
+
+#[cfg(feature = "logging")]
+macro_rules! log_debug {
+    ($($arg:tt)+) => { debug!(target: "mix_link", $($arg)+) }
+}
+
+#[cfg(feature = "logging")]
+macro_rules! log_info {
+    ($($arg:tt)+) => { info!(target: "mix_link", $($arg)+) }
+}
+
+#[cfg(feature = "logging")]
+macro_rules! log_warn {
+    ($($arg:tt)+) => { warn!(target: "mix_link", $($arg)+) }
+}
+
+// Without the feature the arguments are still type checked, so that
+// both configurations build the same way.
+
+#[cfg(not(feature = "logging"))]
+macro_rules! log_debug {
+    ($($arg:tt)+) => { if false { let _ = format_args!($($arg)+); } }
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! log_info {
+    ($($arg:tt)+) => { if false { let _ = format_args!($($arg)+); } }
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! log_warn {
+    ($($arg:tt)+) => { if false { let _ = format_args!($($arg)+); } }
+}
+
+/// Returns a short hex fingerprint of a public key.
+pub fn fingerprint(key: &PublicKey) -> String {
+    let hash = blake2b(8, &[], &key.to_vec());
+    let mut out = String::with_capacity(16);
+    for b in hash.as_bytes() {
+        write!(out, "{:02x}", b).unwrap();
+    }
+    out
+}
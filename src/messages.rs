@@ -17,45 +17,65 @@
 extern crate snow;
 extern crate ecdh_wrapper;
 
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::collections::HashMap;
+use std::fmt;
+use std::str;
+use std::sync::Arc;
 
-use subtle::ConstantTimeEq;
 use byteorder::{ByteOrder, BigEndian};
+use blake2_rfc::blake2b::Blake2b;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 use snow::Builder;
+use snow::SnowError;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
 use ecdh_wrapper::{PrivateKey, PublicKey};
 
-use super::errors::{HandshakeError, AuthenticationError};
+use super::errors::{HandshakeError, AuthenticationError, ExportError, TopologyError};
 use super::errors::{ClientHandshakeError, ServerHandshakeError, ReceiveMessageError, SendMessageError};
+use super::clock::{Clock, SystemClock};
+use super::replay::ReplayCache;
+use super::allowlist::Allowlist;
+use super::epoch::EpochAllowlist;
+use super::topology::Topology;
+use super::rate_limit::RateLimits;
+use super::metrics::Metrics;
+use super::rng::{RandomSource, RandomSourceResolver};
+use super::suite::NoiseSuite;
+use super::cipherstate::{CipherState, KeyRecordingResolver, RecordedKeys};
 
 use super::constants::{NOISE_MESSAGE_MAX_SIZE,
-                       NOISE_MESSAGE_HEADER_SIZE,
-                       NOISE_HANDSHAKE_MESSAGE1_SIZE,
-                       NOISE_HANDSHAKE_MESSAGE2_SIZE,
-                       NOISE_HANDSHAKE_MESSAGE3_SIZE,
-                       NOISE_PARAMS,
-                       PROLOGUE,
                        PROLOGUE_SIZE,
+                       KEY_SIZE,
+                       PSK_SIZE,
+                       MAX_EXPORT_SIZE,
+                       MAX_PROTOCOL_VERSION,
+                       PROTOCOL_VERSION_0,
                        MAC_SIZE,
                        MAX_ADDITIONAL_DATA_SIZE,
-                       AUTH_MESSAGE_SIZE};
+                       NODE_IDENTITY_SIZE,
+                       PROLOGUE,
+                       AUTH_MESSAGE_SIZE,
+                       VERSIONED_AUTH_MESSAGE_SIZE};
 
 #[derive(PartialEq)]
 #[derive(Debug)]
 struct AuthenticateMessage {
     ad: Vec<u8>,
     unix_time: u64, // Seconds since unix epoch.
+    version: Option<u8>, // Negotiated protocol version, absent in the legacy format.
 }
 
 impl AuthenticateMessage {
     pub fn from_bytes(b: &[u8]) -> Result<AuthenticateMessage, AuthenticationError> {
-        if b.len() != AUTH_MESSAGE_SIZE {
-            return Err(AuthenticationError::InvalidSize)
-        }
+        let version = match b.len() {
+            AUTH_MESSAGE_SIZE => None,
+            VERSIONED_AUTH_MESSAGE_SIZE => Some(b[AUTH_MESSAGE_SIZE]),
+            _ => return Err(AuthenticationError::InvalidSize),
+        };
         let ad_len = b[0] as usize;
         Ok(AuthenticateMessage{
             ad: b[1..=ad_len].to_vec(),
-            unix_time: BigEndian::read_u64(&b[1+MAX_ADDITIONAL_DATA_SIZE..]),
+            unix_time: BigEndian::read_u64(&b[1+MAX_ADDITIONAL_DATA_SIZE..AUTH_MESSAGE_SIZE]),
+            version,
         })
     }
 
@@ -71,32 +91,190 @@ impl AuthenticateMessage {
         let mut tmp = vec![0u8; 8];
         BigEndian::write_u64(&mut tmp, self.unix_time);
         b.extend(&tmp);
+        if let Some(version) = self.version {
+            b.push(version);
+        }
         Ok(b)
     }
 }
 
+impl Drop for AuthenticateMessage {
+    fn drop(&mut self) {
+        self.ad.zeroize();
+    }
+}
+
+/// Overwrite a private key in place, PrivateKey does not wipe
+/// itself when dropped.
+fn wipe_private_key(key: &mut PrivateKey) {
+    let _ = key.from_bytes(&[0u8; KEY_SIZE]);
+}
+
+/// AuthenticationKey is a link private key which is wiped when
+/// dropped and redacted from the `Debug` output. The raw key is never
+/// handed out, so a clone is always another wiping AuthenticationKey.
+#[derive(Clone)]
+pub struct AuthenticationKey(PrivateKey);
+
+impl AuthenticationKey {
+    pub fn public_key(&self) -> PublicKey {
+        self.0.public_key()
+    }
+
+    /// Returns the Diffie-Hellman shared secret with the given
+    /// public key, wiped when dropped.
+    pub fn exchange(&self, public_key: &PublicKey) -> Zeroizing<[u8; KEY_SIZE]> {
+        Zeroizing::new(self.0.exchange(public_key))
+    }
+
+    /// Call `f` with the raw private key, which is wiped once `f`
+    /// returns.
+    pub fn with_bytes<F, T>(&self, f: F) -> T where F: FnOnce(&[u8]) -> T {
+        let raw = Zeroizing::new(self.0.to_vec());
+        f(&raw)
+    }
+}
+
+impl From<PrivateKey> for AuthenticationKey {
+    fn from(key: PrivateKey) -> AuthenticationKey {
+        AuthenticationKey(key)
+    }
+}
+
+impl fmt::Debug for AuthenticationKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AuthenticationKey(<redacted>)")
+    }
+}
+
+impl Zeroize for AuthenticationKey {
+    fn zeroize(&mut self) {
+        wipe_private_key(&mut self.0);
+    }
+}
+
+impl Drop for AuthenticationKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for AuthenticationKey {}
+
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub struct PeerCredentials {
+    #[cfg_attr(feature = "serialization", serde(with = "::serialization::bytes"))]
     pub additional_data: Vec<u8>,
+    #[cfg_attr(feature = "serialization", serde(with = "::serialization::public_key"))]
     pub public_key: PublicKey,
 }
 
+impl PeerCredentials {
+    /// Returns the additional data interpreted as the user identity
+    /// that clients present to their Provider.
+    pub fn username(&self) -> Result<&str, AuthenticationError> {
+        if self.additional_data.is_empty() {
+            return Err(AuthenticationError::InvalidUsername);
+        }
+        match str::from_utf8(&self.additional_data) {
+            Ok(x) => Ok(x),
+            Err(_) => Err(AuthenticationError::InvalidUsername),
+        }
+    }
+
+    /// Returns the additional data interpreted as the identity
+    /// key hash that mixes and Providers present to each other.
+    pub fn node_identity(&self) -> Result<[u8; NODE_IDENTITY_SIZE], AuthenticationError> {
+        if self.additional_data.len() != NODE_IDENTITY_SIZE {
+            return Err(AuthenticationError::InvalidNodeIdentity);
+        }
+        let mut id = [0u8; NODE_IDENTITY_SIZE];
+        id.copy_from_slice(&self.additional_data);
+        Ok(id)
+    }
+}
+
+/// AdditionalDataPolicy describes what an authenticator requires
+/// of the additional data sent by a peer.
+#[derive(PartialEq, Debug, Clone)]
+pub enum AdditionalDataPolicy {
+    /// Accept any additional data.
+    Any,
+
+    /// Require a valid username, see `PeerCredentials::username`.
+    Username,
+
+    /// Require a valid node identity, see `PeerCredentials::node_identity`.
+    NodeIdentity,
+}
+
+impl Default for AdditionalDataPolicy {
+    fn default() -> AdditionalDataPolicy {
+        AdditionalDataPolicy::Any
+    }
+}
+
+impl AdditionalDataPolicy {
+    pub fn is_valid(&self, peer_credentials: &PeerCredentials) -> bool {
+        match *self {
+            AdditionalDataPolicy::Any => true,
+            AdditionalDataPolicy::Username => peer_credentials.username().is_ok(),
+            AdditionalDataPolicy::NodeIdentity => peer_credentials.node_identity().is_ok(),
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Default)]
 pub struct ServerAuthenticatorState{
-    pub mix_map: HashMap<PublicKey, bool>,
+    pub mix_map: Allowlist,
+    pub mix_ad_policy: AdditionalDataPolicy,
 }
 
 #[derive(PartialEq, Debug, Clone, Default)]
 pub struct ProviderAuthenticatorState{
-    pub mix_map: HashMap<PublicKey, bool>,
-    pub client_map: HashMap<PublicKey, bool>,
+    pub mix_map: Allowlist,
+    pub client_map: Allowlist,
+    pub mix_ad_policy: AdditionalDataPolicy,
+    pub client_ad_policy: AdditionalDataPolicy,
     pub from_client: bool,
     pub from_mix: bool,
 }
 
+#[derive(PartialEq, Debug, Clone)]
+pub struct EpochAuthenticatorState{
+    pub mix_keys: EpochAllowlist,
+    pub mix_ad_policy: AdditionalDataPolicy,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct TopologyAuthenticatorState{
+    pub topology: Topology,
+    pub mix_ad_policy: AdditionalDataPolicy,
+    /// Whether the local node initiated the link, set by the session.
+    /// Initiators expect the peer in the layer after their own and
+    /// responders in the layer before.
+    pub outbound: bool,
+}
+
+impl TopologyAuthenticatorState {
+    fn check(&self, key: &PublicKey) -> Result<(), TopologyError> {
+        if self.outbound {
+            return self.topology.check_outbound(key)
+        }
+        self.topology.check_inbound(key)
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Default)]
 pub struct ClientAuthenticatorState{
-    pub peer_public_key: PublicKey,
+    /// The pinned peer keys, e.g. both the old and the new key of a
+    /// Provider during a key rotation.
+    pub peer_public_keys: Vec<PublicKey>,
+    pub peer_ad_policy: AdditionalDataPolicy,
+    /// The index of the pinned key which the peer presented, set
+    /// once the peer is authenticated.
+    pub matched_key: Option<usize>,
 }
 
 
@@ -111,19 +289,54 @@ pub enum PeerAuthenticator {
 
     /// An authenticator to be used on a client.
     Client(ClientAuthenticatorState),
+
+    /// An authenticator to be used on a server which accepts the
+    /// mixes listed in the consensus of the current epoch.
+    Epoch(EpochAuthenticatorState),
+
+    /// An authenticator to be used on a node in a stratified topology
+    /// which accepts peers from the layer before its own and connects
+    /// to the layer after it. Clients are not part of the topology, so
+    /// a Provider using it rejects every client, use the Provider
+    /// authenticator on links which accept clients.
+    Topology(TopologyAuthenticatorState),
 }
 
 impl PeerAuthenticator {
     pub fn is_peer_valid(&mut self, peer_credentials: &PeerCredentials) -> bool {
         match *self {
-            PeerAuthenticator::Client(ref state) => state.peer_public_key.eq(&peer_credentials.public_key),
-            PeerAuthenticator::Server(ref state) => state.mix_map.get(&peer_credentials.public_key).is_some(),
+            PeerAuthenticator::Client(ref mut state) => {
+                let index = state.peer_public_keys.iter().position(|x| x.eq(&peer_credentials.public_key));
+                if index.is_none() || !state.peer_ad_policy.is_valid(peer_credentials) {
+                    return false
+                }
+                state.matched_key = index;
+                true
+            },
+            PeerAuthenticator::Server(ref state) => {
+                state.mix_map.contains(&peer_credentials.public_key) &&
+                    state.mix_ad_policy.is_valid(peer_credentials)
+            },
+            PeerAuthenticator::Epoch(ref state) => {
+                state.mix_keys.is_valid(&peer_credentials.public_key) &&
+                    state.mix_ad_policy.is_valid(peer_credentials)
+            },
+            PeerAuthenticator::Topology(ref state) => {
+                state.check(&peer_credentials.public_key).is_ok() &&
+                    state.mix_ad_policy.is_valid(peer_credentials)
+            },
             PeerAuthenticator::Provider(ref mut state) => {
-                if state.mix_map.get(&peer_credentials.public_key).is_some() {
+                if state.mix_map.contains(&peer_credentials.public_key) {
+                    if !state.mix_ad_policy.is_valid(peer_credentials) {
+                        return false
+                    }
                     state.from_mix = true;
                     return true
                 }
-                if state.client_map.get(&peer_credentials.public_key).is_some() {
+                if state.client_map.contains(&peer_credentials.public_key) {
+                    if !state.client_ad_policy.is_valid(peer_credentials) {
+                        return false
+                    }
                     state.from_client = true;
                     return true
                 }
@@ -136,12 +349,46 @@ impl PeerAuthenticator {
         match *self {
             PeerAuthenticator::Client(ref _state) => return false,
             PeerAuthenticator::Server(ref _state) => return false,
+            PeerAuthenticator::Epoch(ref _state) => return false,
+            PeerAuthenticator::Topology(ref _state) => return false,
             PeerAuthenticator::Provider(ref state) => return state.from_client,
         }
     }
+
+    /// Returns the index of the pinned key which the peer presented
+    /// to a client authenticator.
+    pub fn matched_key(&self) -> Option<usize> {
+        match *self {
+            PeerAuthenticator::Client(ref state) => state.matched_key,
+            _ => None,
+        }
+    }
+
+    /// Returns why a topology authenticator rejects the peer, if it
+    /// does.
+    pub fn topology_error(&self, peer_credentials: &PeerCredentials) -> Option<TopologyError> {
+        match *self {
+            PeerAuthenticator::Topology(ref state) => state.check(&peer_credentials.public_key).err(),
+            _ => None,
+        }
+    }
+
+    /// Returns the allowlist which admitted the peer, if any.
+    pub fn allowlist(&self) -> Option<&Allowlist> {
+        match *self {
+            PeerAuthenticator::Client(_) => None,
+            PeerAuthenticator::Epoch(_) => None,
+            PeerAuthenticator::Topology(_) => None,
+            PeerAuthenticator::Server(ref state) => Some(&state.mix_map),
+            PeerAuthenticator::Provider(ref state) if state.from_mix => Some(&state.mix_map),
+            PeerAuthenticator::Provider(ref state) if state.from_client => Some(&state.client_map),
+            PeerAuthenticator::Provider(_) => None,
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub enum State {
     Init,
     SentClientHandshake1,
@@ -153,74 +400,300 @@ pub enum State {
     Invalid,
 }
 
-/// A session configuration type.
-#[derive(PartialEq, Debug, Clone)]
+/// A session configuration type. The private key and pre-shared
+/// key wipe themselves on drop, clones included, and are redacted
+/// from the `Debug` output.
+#[derive(Clone)]
 pub struct SessionConfig {
     pub authenticator: PeerAuthenticator,
-    pub authentication_key: PrivateKey,
+    pub authentication_key: AuthenticationKey,
     pub peer_public_key: Option<PublicKey>,
     pub additional_data: Vec<u8>,
+    /// A responder side cache of recently seen handshakes, shared
+    /// by all sessions of a listener. When set, replayed handshakes
+    /// and timestamps outside of the cache window are rejected. A
+    /// zero timestamp is only accepted from clients and from peers
+    /// speaking version 0.
+    pub replay_cache: Option<ReplayCache>,
+    /// The protocol versions we are willing to speak. The initiator
+    /// advertises them in the prologue and the responder picks the
+    /// highest version both sides support. Offering only version 0
+    /// keeps the handshake compatible with peers which predate
+    /// version negotiation.
+    pub protocol_versions: Vec<u8>,
+    /// The Noise cipher and hash functions, which must match the peer's.
+    pub noise_suite: NoiseSuite,
+    /// An optional network wide pre-shared key. When set the `XXpsk3`
+    /// pattern is used and only peers holding the same key can
+    /// complete a handshake. The key is mixed into the final
+    /// handshake message, so a different key is reported by the
+    /// responder while the initiator only sees the link close.
+    pub psk: Option<Zeroizing<[u8; PSK_SIZE]>>,
+    /// The time source for handshake timestamps, the system clock
+    /// if unset.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// The randomness for Noise ephemeral keys, snow's default
+    /// RNG if unset.
+    pub rng: Option<Arc<dyn RandomSource>>,
+    /// Limits on the commands accepted from the peer once the
+    /// session is in transport mode, chosen by the peer's role.
+    pub rate_limits: RateLimits,
+    /// Where to report the session's events, if anywhere.
+    pub metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl fmt::Debug for SessionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SessionConfig")
+            .field("authenticator", &self.authenticator)
+            .field("authentication_key", &self.authentication_key)
+            .field("peer_public_key", &self.peer_public_key)
+            .field("additional_data", &self.additional_data)
+            .field("replay_cache", &self.replay_cache)
+            .field("protocol_versions", &self.protocol_versions)
+            .field("noise_suite", &self.noise_suite)
+            .field("psk", &self.psk.as_ref().map(|_| "<redacted>"))
+            .field("clock", &self.clock)
+            .field("rng", &self.rng)
+            .field("rate_limits", &self.rate_limits)
+            .field("metrics", &self.metrics)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+impl SessionConfig {
+    /// A config with the default protocol settings, shared by the
+    /// tests of each module which runs a handshake.
+    pub(crate) fn test_config(authenticator: PeerAuthenticator, authentication_key: PrivateKey, peer_public_key: Option<PublicKey>) -> SessionConfig {
+        SessionConfig {
+            authenticator,
+            authentication_key: authentication_key.into(),
+            peer_public_key,
+            additional_data: vec![],
+            replay_cache: None,
+            protocol_versions: super::constants::SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            noise_suite: NoiseSuite::default(),
+            psk: None,
+            clock: None,
+            rng: None,
+            rate_limits: RateLimits::default(),
+            metrics: None,
+        }
+    }
+}
+
+/// The key material held by a MessageBuilder, kept apart so that
+/// it is wiped on drop even as the builder changes modes.
+struct SessionSecrets {
+    authentication_key: AuthenticationKey,
+    psk: Option<Zeroizing<[u8; PSK_SIZE]>>,
+    handshake_hash: Option<Vec<u8>>,
+    exporter_secret: Option<Vec<u8>>,
+}
+
+impl fmt::Debug for SessionSecrets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SessionSecrets {{ <redacted> }}")
+    }
+}
+
+impl Drop for SessionSecrets {
+    fn drop(&mut self) {
+        if let Some(ref mut handshake_hash) = self.handshake_hash {
+            handshake_hash.zeroize();
+        }
+        if let Some(ref mut exporter_secret) = self.exporter_secret {
+            exporter_secret.zeroize();
+        }
+    }
+}
+
+/// The Noise state of a MessageBuilder, a handshake until it is
+/// split into a cipher state for each direction.
+#[derive(Debug)]
+enum Noise {
+    Handshake(snow::Session, RecordedKeys),
+    Transport(CipherState, CipherState),
 }
 
 /// A cryptographic protocol message factory type.
 #[derive(Debug)]
 pub struct MessageBuilder {
-    session: snow::Session,
+    noise: Noise,
     state: State,
     additional_data: Vec<u8>,
     pub authenticator: PeerAuthenticator,
     is_initiator: bool,
     clock_skew: u64,
     peer_credentials: Option<Box<PeerCredentials>>,
+    replay_cache: Option<ReplayCache>,
+    /// The initiator's ephemeral key, recorded in the replay cache
+    /// once the initiator has authenticated.
+    initiator_ephemeral: Option<Vec<u8>>,
+    supported_versions: u8,
+    prologue: u8,
+    protocol_version: Option<u8>,
+    suite: NoiseSuite,
+    secrets: SessionSecrets,
+    clock: Arc<dyn Clock>,
+    rng: Option<Arc<dyn RandomSource>>,
+}
+
+/// Encode a set of protocol versions as a bit mask.
+fn versions_to_mask(versions: &[u8]) -> Result<u8, HandshakeError> {
+    let mut mask = 0u8;
+    for version in versions {
+        if *version > MAX_PROTOCOL_VERSION {
+            return Err(HandshakeError::InvalidProtocolVersionError);
+        }
+        mask |= 1 << *version;
+    }
+    if mask == 0 {
+        return Err(HandshakeError::InvalidProtocolVersionError);
+    }
+    Ok(mask)
+}
+
+/// Peers which only speak version 0 send the legacy prologue, so
+/// that their handshake is byte identical to the original protocol.
+/// Any other set of versions is advertised as its bit mask.
+fn mask_to_prologue(mask: u8) -> u8 {
+    if mask == 1 << PROTOCOL_VERSION_0 {
+        return PROLOGUE[0]
+    }
+    mask
+}
+
+fn prologue_to_mask(prologue: u8) -> u8 {
+    if prologue == PROLOGUE[0] {
+        return 1 << PROTOCOL_VERSION_0
+    }
+    prologue
+}
+
+fn is_version_in_mask(version: u8, mask: u8) -> bool {
+    version <= MAX_PROTOCOL_VERSION && mask & (1 << version) != 0
+}
+
+/// Derive `length` bytes from the given exporter secret. Each 64 byte
+/// block is a BLAKE2b MAC keyed with the secret over the length
+/// prefixed label and context and a block counter.
+fn export_keying_material(secret: &[u8], label: &[u8], context: &[u8], length: usize) -> Result<Vec<u8>, ExportError> {
+    if length == 0 || length > MAX_EXPORT_SIZE || label.len() > 255 || context.len() > 255 {
+        return Err(ExportError::InvalidLengthError);
+    }
+    let mut output = Vec::with_capacity(length);
+    let mut counter = 1u8;
+    while output.len() < length {
+        let mut hasher = Blake2b::with_key(64, secret);
+        hasher.update(b"mix_link exporter");
+        hasher.update(&[label.len() as u8]);
+        hasher.update(label);
+        hasher.update(&[context.len() as u8]);
+        hasher.update(context);
+        hasher.update(&[counter]);
+        let block = hasher.finalize();
+        let needed = length - output.len();
+        output.extend_from_slice(&block.as_bytes()[..needed.min(64)]);
+        counter = counter.wrapping_add(1);
+    }
+    Ok(output)
+}
+
+fn build_noise_session(suite: &NoiseSuite, psk: Option<&Zeroizing<[u8; PSK_SIZE]>>, rng: Option<&Arc<dyn RandomSource>>, authentication_key: &AuthenticationKey, is_initiator: bool, peer_public_key: Option<&PublicKey>, prologue: &[u8]) -> Result<Noise, HandshakeError> {
+    let noise_params;
+    match suite.noise_params(psk.is_some()).parse() {
+        Ok(x) => {
+            noise_params = x;
+        },
+        Err(_) => return Err(HandshakeError::InvalidNoiseSpecError),
+    }
+    let resolver: Box<dyn CryptoResolver> = match rng {
+        Some(rng) => Box::new(RandomSourceResolver::new(rng.clone())),
+        None => Box::new(DefaultResolver),
+    };
+    let resolver = KeyRecordingResolver::new(resolver);
+    let keys = resolver.keys();
+    let mut noise_builder = Builder::with_resolver(noise_params, Box::new(resolver));
+    if let Some(psk) = psk {
+        noise_builder = noise_builder.psk(3, &psk[..]);
+    }
+    let remote_key = peer_public_key.map(|x| x.to_vec());
+    let session = authentication_key.with_bytes(|local_key| {
+        let mut noise_builder = noise_builder.local_private_key(local_key).prologue(prologue);
+        if let Some(ref remote_key) = remote_key {
+            noise_builder = noise_builder.remote_public_key(remote_key);
+        }
+        if is_initiator {
+            noise_builder.build_initiator()
+        } else {
+            noise_builder.build_responder()
+        }
+    });
+    match session {
+        Ok(x) => Ok(Noise::Handshake(x, keys)),
+        Err(_) => Err(HandshakeError::SessionCreateError),
+    }
 }
 
 impl MessageBuilder {
     pub fn new(config: SessionConfig, is_initiator: bool) -> Result<MessageBuilder, HandshakeError> {
-        let noise_params;
-        match NOISE_PARAMS.parse() {
-            Ok(x) => {
-                noise_params = x;
-            },
-            Err(_) => return Err(HandshakeError::InvalidNoiseSpecError),
-        }
-        let noise_builder: Builder = Builder::new(noise_params);
+        let supported_versions = versions_to_mask(&config.protocol_versions)?;
+        let prologue = mask_to_prologue(supported_versions);
+        let noise;
         if is_initiator {
-            if config.peer_public_key.is_none() {
+            // The XX pattern learns the responder's key during the
+            // handshake, so pinned keys can stand in for a peer key.
+            let pinned = match config.authenticator {
+                PeerAuthenticator::Client(ref state) => !state.peer_public_keys.is_empty(),
+                _ => false,
+            };
+            if config.peer_public_key.is_none() && !pinned {
                 return Err(HandshakeError::NoPeerKeyError);
             }
-            let session = match noise_builder
-                .local_private_key(&config.authentication_key.to_vec())
-                .remote_public_key(&(config.peer_public_key.unwrap()).to_vec())
-                .prologue(&PROLOGUE)
-                .build_initiator() {
-                    Ok(x) => x,
-                    Err(_) => return Err(HandshakeError::SessionCreateError),
-                };
-            return Ok(MessageBuilder {
-                state: State::Init,
-                additional_data: config.additional_data,
-                authenticator: config.authenticator,
-                session,
-                is_initiator,
-                clock_skew: 0,
-                peer_credentials: None,
-            });
-        }
-        let session = match noise_builder
-            .local_private_key(&config.authentication_key.to_vec())
-            .prologue(&PROLOGUE)
-            .build_responder() {
-                Ok(x) => x,
-                Err(_) => return Err(HandshakeError::SessionCreateError),
-            };
+            noise = build_noise_session(&config.noise_suite,
+                                          config.psk.as_ref(),
+                                          config.rng.as_ref(),
+                                          &config.authentication_key,
+                                          true,
+                                          config.peer_public_key.as_ref(),
+                                          &[prologue])?;
+        } else {
+            // The responder rebuilds this session with the initiator's
+            // prologue if the advertised versions differ from ours.
+            noise = build_noise_session(&config.noise_suite, config.psk.as_ref(), config.rng.as_ref(),
+                                          &config.authentication_key, false, None, &[prologue])?;
+        }
+        let mut authenticator = config.authenticator;
+        if let PeerAuthenticator::Topology(ref mut state) = authenticator {
+            state.outbound = is_initiator;
+        }
         Ok(MessageBuilder {
             state: State::Init,
             additional_data: config.additional_data,
-            authenticator: config.authenticator,
-            session,
+            authenticator,
+            noise,
             is_initiator,
             clock_skew: 0,
             peer_credentials: None,
+            replay_cache: config.replay_cache,
+            initiator_ephemeral: None,
+            supported_versions,
+            prologue,
+            protocol_version: None,
+            suite: config.noise_suite,
+            secrets: SessionSecrets {
+                authentication_key: config.authentication_key,
+                psk: config.psk,
+                handshake_hash: None,
+                exporter_secret: None,
+            },
+            clock: match config.clock {
+                Some(clock) => clock,
+                None => Arc::new(SystemClock),
+            },
+            rng: config.rng,
         })
     }
 
@@ -228,19 +701,66 @@ impl MessageBuilder {
         self.peer_credentials.as_ref().unwrap()
     }
 
+    /// Returns the peer's static public key once it has been received.
+    pub fn peer_public_key(&self) -> Option<&PublicKey> {
+        self.peer_credentials.as_ref().map(|x| &x.public_key)
+    }
+
     pub fn clock_skew(&self) -> u64 {
         self.clock_skew
     }
 
-    pub fn client_handshake1(&mut self) -> Result<[u8; NOISE_HANDSHAKE_MESSAGE1_SIZE], ClientHandshakeError> {
+    /// Returns the negotiated protocol version once the peer's
+    /// handshake message selecting or confirming it was received.
+    pub fn protocol_version(&self) -> Option<u8> {
+        self.protocol_version
+    }
+
+    /// Returns the Noise suite, which determines handshake message sizes.
+    pub fn suite(&self) -> &NoiseSuite {
+        &self.suite
+    }
+
+    /// Returns the size of the first handshake message, which
+    /// depends on whether a pre-shared key is in use.
+    pub fn handshake_message1_size(&self) -> usize {
+        self.suite.handshake_message1_size(self.secrets.psk.is_some())
+    }
+
+    /// Returns the size of the second handshake message. It depends
+    /// on the prologue, so the responder only knows it once the first
+    /// message has been received.
+    pub fn handshake_message2_size(&self) -> usize {
+        self.suite.handshake_message2_size(self.is_versioned())
+    }
+
+    /// Returns the size of the third handshake message.
+    pub fn handshake_message3_size(&self) -> usize {
+        self.suite.handshake_message3_size(self.is_versioned())
+    }
+
+    /// The authenticate messages only carry the protocol version
+    /// when the initiator did not send the legacy prologue.
+    fn is_versioned(&self) -> bool {
+        self.prologue != PROLOGUE[0]
+    }
+
+    fn handshake_session<E>(&mut self, err: E) -> Result<&mut snow::Session, E> {
+        match self.noise {
+            Noise::Handshake(ref mut session, _) => Ok(session),
+            Noise::Transport(..) => Err(err),
+        }
+    }
+
+    pub fn client_handshake1(&mut self) -> Result<Vec<u8>, ClientHandshakeError> {
 	// -> (prologue), e, f
         let mut msg = [0u8; NOISE_MESSAGE_MAX_SIZE];
-        let _len = match self.session.write_message(&[0u8;0], &mut msg) {
+        let _len = match self.handshake_session(ClientHandshakeError::InvalidStateError)?.write_message(&[0u8;0], &mut msg) {
             Ok(x) => x,
             Err(_) => return Err(ClientHandshakeError::Noise1WriteError),
         };
-        let mut msg1 = [0u8; NOISE_HANDSHAKE_MESSAGE1_SIZE];
-        msg1[0] = PROLOGUE[0];
+        let mut msg1 = vec![0u8; self.handshake_message1_size()];
+        msg1[0] = self.prologue;
         msg1[PROLOGUE_SIZE..].copy_from_slice(&msg[.._len]);
         Ok(msg1)
     }
@@ -253,20 +773,34 @@ impl MessageBuilder {
         self.state = State::DataTransfer;
     }
 
-    pub fn received_server_handshake1(&mut self, message: [u8; NOISE_HANDSHAKE_MESSAGE2_SIZE]) -> Result<(), ClientHandshakeError> {
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        let mut raw_auth = [0u8; AUTH_MESSAGE_SIZE];
-        let _len = match self.session.read_message(&message, &mut raw_auth) {
+    pub fn received_server_handshake1(&mut self, message: &[u8]) -> Result<(), ClientHandshakeError> {
+        if message.len() != self.handshake_message2_size() {
+            return Err(ClientHandshakeError::Noise2ReadError);
+        }
+        let now = self.clock.now();
+        let mut raw_auth = [0u8; VERSIONED_AUTH_MESSAGE_SIZE];
+        let _len = match self.handshake_session(ClientHandshakeError::InvalidStateError)?.read_message(message, &mut raw_auth) {
             Ok(x) => x,
+            // A different pre-shared key or suite, which are both part
+            // of the transcript, fails here just like a corrupted message.
+            Err(SnowError::Decrypt) => return Err(ClientHandshakeError::DecryptError),
             Err(_) => return Err(ClientHandshakeError::Noise2ReadError),
         };
-        let peer_auth = match AuthenticateMessage::from_bytes(&raw_auth) {
+        let peer_auth = AuthenticateMessage::from_bytes(&raw_auth[.._len]);
+        raw_auth.zeroize();
+        let peer_auth = match peer_auth {
             Ok(x) => x,
             Err(_) => return Err(ClientHandshakeError::AuthenticationError),
         };
+        // A legacy handshake implies version 0.
+        let version = peer_auth.version.unwrap_or(PROTOCOL_VERSION_0);
+        if !is_version_in_mask(version, self.supported_versions) {
+            return Err(ClientHandshakeError::VersionMismatchError);
+        }
+        self.protocol_version = Some(version);
 
         // Authenticate the peer.
-        let raw_peer_key = match self.session.get_remote_static() {
+        let raw_peer_key = match self.handshake_session(ClientHandshakeError::InvalidStateError)?.get_remote_static() {
             Some(x) => x,
             None => return Err(ClientHandshakeError::FailedToGetRemoteStatic),
         };
@@ -276,49 +810,92 @@ impl MessageBuilder {
             Err(_y) => return Err(ClientHandshakeError::FailedToDecodeRemoteStatic),
         }
         self.peer_credentials = Some(Box::new(PeerCredentials {
-            additional_data: peer_auth.ad,
+            additional_data: peer_auth.ad.clone(),
             public_key: peer_key,
         }));
         let peer_key = self.peer_credentials.as_ref().unwrap();
+        if let Some(err) = self.authenticator.topology_error(peer_key) {
+            return Err(ClientHandshakeError::TopologyError(err));
+        }
         if !self.authenticator.is_peer_valid(peer_key) {
             return Err(ClientHandshakeError::AuthenticationError);
         }
 
         // Cache the clock skew.
         let peer_clock = peer_auth.unix_time;
-        self.clock_skew = now - peer_clock;
+        self.clock_skew = if now > peer_clock {
+            now - peer_clock
+        } else {
+            peer_clock - now
+        };
 
         self.state = State::ReceivedServerHandshake1;
         Ok(())
     }
 
-    pub fn client_handshake2(&mut self) -> Result<[u8; NOISE_HANDSHAKE_MESSAGE3_SIZE], ClientHandshakeError> {
+    pub fn client_handshake2(&mut self) -> Result<Vec<u8>, ClientHandshakeError> {
         let mut msg = [0u8; NOISE_MESSAGE_MAX_SIZE];
-        let our_auth = AuthenticateMessage {
-            ad: self.additional_data.clone(),
+        let unix_time = match self.authenticator {
             // Clients should always send a zero unix_time so they don't
             // leak their system time to the peer.
-            unix_time: 0,
+            PeerAuthenticator::Client(_) => 0,
+            // Mixes and Providers timestamp their handshake so that the
+            // responder can reject stale handshakes.
+            _ => self.clock.now(),
+        };
+        let our_auth = AuthenticateMessage {
+            ad: self.additional_data.clone(),
+            unix_time,
+            version: match self.protocol_version {
+                Some(_) if !self.is_versioned() => None,
+                Some(x) => Some(x),
+                None => return Err(ClientHandshakeError::InvalidStateError),
+            },
         };
-        let _len = match self.session.write_message(&our_auth.to_vec().unwrap(), &mut msg) {
+        let _len = match self.handshake_session(ClientHandshakeError::InvalidStateError)?.write_message(&our_auth.to_vec().unwrap(), &mut msg) {
             Ok(x) => x,
             Err(_) => return Err(ClientHandshakeError::Noise3WriteError),
         };
-        assert_eq!(NOISE_HANDSHAKE_MESSAGE3_SIZE, _len);
-        let mut _msg3 = [0u8; NOISE_HANDSHAKE_MESSAGE3_SIZE];
-        _msg3.copy_from_slice(&msg[..NOISE_HANDSHAKE_MESSAGE3_SIZE]);
-        Ok(_msg3)
+        assert_eq!(self.handshake_message3_size(), _len);
+        Ok(msg[.._len].to_vec())
     }
 
-    pub fn received_client_handshake1(&mut self, message: [u8; NOISE_HANDSHAKE_MESSAGE1_SIZE]) -> Result<[u8; NOISE_HANDSHAKE_MESSAGE2_SIZE], ServerHandshakeError> {
+    pub fn received_client_handshake1(&mut self, message: &[u8]) -> Result<Vec<u8>, ServerHandshakeError> {
         if self.state != State::Init {
             return Err(ServerHandshakeError::InvalidStateError);
         }
-        if message[0..PROLOGUE_SIZE].ct_eq(&PROLOGUE).unwrap_u8() == 0 {
-            return Err(ServerHandshakeError::PrologueMismatchError);
+        if message.len() != self.handshake_message1_size() {
+            return Err(ServerHandshakeError::Noise1ReadError);
         }
-        let mut _msg = [0u8; NOISE_HANDSHAKE_MESSAGE1_SIZE];
-        let _len = match self.session.read_message(&message[PROLOGUE_SIZE..], &mut _msg) {
+        // Pick the highest protocol version we have in common and
+        // bind the initiator's advertisement into the transcript.
+        let prologue = message[0];
+        let common_versions = prologue_to_mask(prologue) & self.supported_versions;
+        if common_versions == 0 {
+            if prologue == PROLOGUE[0] {
+                // A legacy peer which cannot negotiate.
+                return Err(ServerHandshakeError::PrologueMismatchError);
+            }
+            return Err(ServerHandshakeError::NoCommonVersionError);
+        }
+        if prologue != self.prologue {
+            self.prologue = prologue;
+            self.noise = match build_noise_session(&self.suite, self.secrets.psk.as_ref(), self.rng.as_ref(), &self.secrets.authentication_key, false, None, &message[..PROLOGUE_SIZE]) {
+                Ok(x) => x,
+                Err(_) => return Err(ServerHandshakeError::SessionCreateError),
+            };
+        }
+        let version = (7 - common_versions.leading_zeros()) as u8;
+        self.protocol_version = Some(version);
+        if let Some(ref cache) = self.replay_cache {
+            // The key is only recorded once the initiator has
+            // authenticated, see received_client_handshake2.
+            let ephemeral_key = &message[PROLOGUE_SIZE..PROLOGUE_SIZE+self.suite.dh_size()];
+            cache.check(ephemeral_key, self.clock.now())?;
+            self.initiator_ephemeral = Some(ephemeral_key.to_vec());
+        }
+        let mut _msg = [0u8; NOISE_MESSAGE_MAX_SIZE];
+        let _len = match self.handshake_session(ServerHandshakeError::InvalidStateError)?.read_message(&message[PROLOGUE_SIZE..], &mut _msg) {
             Ok(x) => x,
             Err(_) => return Err(ServerHandshakeError::Noise1ReadError),
         };
@@ -327,118 +904,263 @@ impl MessageBuilder {
         // send server's handshake1 message
         let our_auth = AuthenticateMessage {
             ad: self.additional_data.clone(),
-            unix_time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            unix_time: self.clock.now(),
+            version: if self.is_versioned() {
+                Some(version)
+            } else {
+                None
+            },
         };
-        let mut mesg = [0u8; NOISE_HANDSHAKE_MESSAGE2_SIZE];
-        let mut _len = match self.session.write_message(&our_auth.to_vec().unwrap(), &mut mesg) {
+        let mut mesg = [0u8; NOISE_MESSAGE_MAX_SIZE];
+        let mut _len = match self.handshake_session(ServerHandshakeError::InvalidStateError)?.write_message(&our_auth.to_vec().unwrap(), &mut mesg) {
             Ok(x) => x,
             Err(_) => return Err(ServerHandshakeError::Noise2WriteError),
         };
-        assert_eq!(NOISE_HANDSHAKE_MESSAGE2_SIZE, _len);
-        Ok(mesg)
+        assert_eq!(self.handshake_message2_size(), _len);
+        Ok(mesg[.._len].to_vec())
     }
 
     pub fn sent_server_handshake1(&mut self) {
         self.state = State::SentServerHandshake1;
     }
 
-    pub fn received_client_handshake2(&mut self, message: [u8; NOISE_HANDSHAKE_MESSAGE3_SIZE]) -> Result<(), ServerHandshakeError> {
+    pub fn received_client_handshake2(&mut self, message: &[u8]) -> Result<(), ServerHandshakeError> {
         if self.state != State::SentServerHandshake1 {
             return Err(ServerHandshakeError::InvalidStateError);
         }
-        let mut raw_auth = [0u8; AUTH_MESSAGE_SIZE];
-        let _match = self.session.read_message(&message, &mut raw_auth);
-        match _match {
+        if message.len() != self.handshake_message3_size() {
+            return Err(ServerHandshakeError::Noise3ReadError);
+        }
+        let mut raw_auth = [0u8; VERSIONED_AUTH_MESSAGE_SIZE];
+        let _match = self.handshake_session(ServerHandshakeError::InvalidStateError)?.read_message(message, &mut raw_auth);
+        let _len = match _match {
             Ok(x) => x,
+            // The pre-shared key is mixed in just before this payload
+            // is encrypted, so a peer with a different key fails here,
+            // as does a corrupted message.
+            Err(SnowError::Decrypt) => return Err(ServerHandshakeError::DecryptError),
             Err(_) => return Err(ServerHandshakeError::Noise3ReadError),
         };
-        let peer_auth = AuthenticateMessage::from_bytes(&raw_auth).unwrap();
-        let raw_peer_key = self.session.get_remote_static().unwrap();
+        let peer_auth = AuthenticateMessage::from_bytes(&raw_auth[.._len]).unwrap();
+        raw_auth.zeroize();
+        if Some(peer_auth.version.unwrap_or(PROTOCOL_VERSION_0)) != self.protocol_version {
+            return Err(ServerHandshakeError::VersionMismatchError);
+        }
+        let raw_peer_key = self.handshake_session(ServerHandshakeError::InvalidStateError)?.get_remote_static().unwrap();
         let mut peer_key = PublicKey::default();
         match peer_key.from_bytes(raw_peer_key) {
             Ok(_) => {},
             Err(_) => return Err(ServerHandshakeError::FailedToDecodeRemoteStatic),
         }
         self.peer_credentials = Some(Box::new(PeerCredentials {
-            additional_data: peer_auth.ad,
+            additional_data: peer_auth.ad.clone(),
             public_key: peer_key,
         }));
         let peer_key = self.peer_credentials.as_ref().unwrap();
+        if let Some(err) = self.authenticator.topology_error(peer_key) {
+            return Err(ServerHandshakeError::TopologyError(err));
+        }
         if !self.authenticator.is_peer_valid(peer_key) {
             return Err(ServerHandshakeError::AuthenticationError);
         }
+        if let Some(ref cache) = self.replay_cache {
+            // Clients send a zero timestamp, and so do mixes and
+            // providers which predate timestamped handshakes and can
+            // therefore only speak version 0. Everyone else must be
+            // within the replay window.
+            if peer_auth.unix_time == 0 {
+                if !self.authenticator.is_peer_client() && self.protocol_version != Some(PROTOCOL_VERSION_0) {
+                    return Err(ServerHandshakeError::StaleTimestampError);
+                }
+            } else {
+                let now = self.clock.now();
+                let skew = if now > peer_auth.unix_time {
+                    now - peer_auth.unix_time
+                } else {
+                    peer_auth.unix_time - now
+                };
+                if skew > cache.window() {
+                    return Err(ServerHandshakeError::StaleTimestampError);
+                }
+            }
+            if let Some(ref ephemeral_key) = self.initiator_ephemeral {
+                cache.insert(ephemeral_key, self.clock.now())?;
+            }
+        }
         self.state = State::DataTransfer;
         Ok(())
     }
 
+    /// Returns the Noise handshake hash once the handshake has
+    /// completed. It uniquely identifies this link and may be used
+    /// for channel binding by higher layers, but it is not secret:
+    /// anyone who observed the handshake can compute it.
+    pub fn handshake_hash(&self) -> Option<&[u8]> {
+        self.secrets.handshake_hash.as_ref().map(|x| x.as_slice())
+    }
+
+    /// Derive `length` bytes of secret keying material from the
+    /// transport keys of the link, a label and a context. Both ends
+    /// of a link derive the same bytes for the same label and context.
+    pub fn export_keying_material(&self, label: &[u8], context: &[u8], length: usize) -> Result<Vec<u8>, ExportError> {
+        match self.secrets.exporter_secret {
+            Some(ref secret) => export_keying_material(secret, label, context, length),
+            None => Err(ExportError::HandshakeIncompleteError),
+        }
+    }
+
     pub fn into_transport_mode(self) -> Result<Self, HandshakeError> {
-        // Transition into transport mode after handshake is finished.
+        // The handshake hash is unavailable after the transition.
+        let mut secrets = self.secrets;
+        let split = match self.noise {
+            Noise::Handshake(session, keys) => {
+                secrets.handshake_hash = Some(session.get_handshake_hash()?.to_vec());
+                let session = session.into_stateless_transport_mode()?;
+                keys.split(&session, &self.suite.cipher_choice())?
+            },
+            Noise::Transport(..) => return Err(HandshakeError::InvalidStateError),
+        };
+        secrets.exporter_secret = Some(split.exporter_secret.clone());
         Ok(Self {
-            session: self.session.into_transport_mode()?,
+            noise: Noise::Transport(split.sender, split.receiver),
             state: self.state,
             additional_data: self.additional_data,
             authenticator: self.authenticator,
             is_initiator: self.is_initiator,
             clock_skew: self.clock_skew,
             peer_credentials: self.peer_credentials,
+            replay_cache: self.replay_cache,
+            initiator_ephemeral: self.initiator_ephemeral,
+            supported_versions: self.supported_versions,
+            prologue: self.prologue,
+            protocol_version: self.protocol_version,
+            suite: self.suite,
+            secrets,
+            clock: self.clock,
+            rng: self.rng,
         })
     }
 
     pub fn encrypt_message(&mut self, message: &[u8]) -> Result<Vec<u8>, SendMessageError> {
-        let ct_len = MAC_SIZE + message.len();
-        if ct_len > NOISE_MESSAGE_MAX_SIZE {
-            return Err(SendMessageError::InvalidMessageSize);
-        }
-        let mut ct_hdr = [0u8; 4];
-        BigEndian::write_u32(&mut ct_hdr, ct_len as u32);
-        let mut ciphertext_header = [0u8; NOISE_MESSAGE_MAX_SIZE];
-        let _result = self.session.write_message(&ct_hdr, &mut ciphertext_header);
-        let _header_len;
-        match _result {
-            Ok(x) => {
-                _header_len = x;
-            },
-            Err(_) => {
-                return Err(SendMessageError::EncryptFail)
-            },
-        }
-        let mut ciphertext = [0u8; NOISE_MESSAGE_MAX_SIZE];
-        let _result = self.session.write_message(&message, &mut ciphertext);
-        let mut _payload_len;
-        match _result {
-            Ok(x) => {
-                _payload_len = x;
-            },
-            Err(_) => {
-                return Err(SendMessageError::EncryptFail)
-            },
+        match self.noise {
+            Noise::Transport(ref mut sender, _) => encrypt_message(sender, message),
+            Noise::Handshake(..) => Err(SendMessageError::EncryptFail),
         }
-        let mut output = Vec::new();
-        output.extend_from_slice(&ciphertext_header[.._header_len]);
-        output.extend_from_slice(&ciphertext[.._payload_len]);
-        Ok(output)
     }
 
     pub fn decrypt_message_header(&mut self, message: &[u8]) -> Result<u32, ReceiveMessageError> {
-        let mut header = [0u8; NOISE_MESSAGE_MAX_SIZE];
-        match self.session.read_message(&message[..NOISE_MESSAGE_HEADER_SIZE], &mut header) {
-            Ok(x) => {
-                assert_eq!(x, 4);
-                Ok(BigEndian::read_u32(&header[..NOISE_MESSAGE_HEADER_SIZE]))
-            },
-            Err(_) => Err(ReceiveMessageError::DecryptFail),
+        match self.noise {
+            Noise::Transport(_, ref mut receiver) => decrypt_message_header(receiver, &self.suite, message),
+            Noise::Handshake(..) => Err(ReceiveMessageError::DecryptFail),
         }
     }
 
     pub fn decrypt_message(&mut self, message: &[u8]) -> Result<Vec<u8>, ReceiveMessageError> {
-        let mut plaintext = [0u8; NOISE_MESSAGE_MAX_SIZE];
-        match self.session.read_message(&message, &mut plaintext) {
-            Ok(_len) => Ok(plaintext[.._len].to_vec()),
-            Err(_) => Err(ReceiveMessageError::DecryptFail),
+        match self.noise {
+            Noise::Transport(_, ref mut receiver) => decrypt_message(receiver, message),
+            Noise::Handshake(..) => Err(ReceiveMessageError::DecryptFail),
+        }
+    }
+
+    /// Split a builder in transport mode into halves which encrypt
+    /// and decrypt independently, each owning the cipher state of
+    /// its direction.
+    pub fn split(self) -> Result<(MessageSender, MessageReceiver), HandshakeError> {
+        if self.state != State::DataTransfer {
+            return Err(HandshakeError::InvalidStateError);
+        }
+        match self.noise {
+            Noise::Transport(sender, receiver) => Ok((MessageSender {
+                cipher: sender,
+            }, MessageReceiver {
+                cipher: receiver,
+                suite: self.suite,
+            })),
+            Noise::Handshake(..) => Err(HandshakeError::InvalidStateError),
         }
     }
 }
 
+/// The sending half of a split MessageBuilder.
+#[derive(Debug)]
+pub struct MessageSender {
+    cipher: CipherState,
+}
+
+impl MessageSender {
+    pub fn encrypt_message(&mut self, message: &[u8]) -> Result<Vec<u8>, SendMessageError> {
+        encrypt_message(&mut self.cipher, message)
+    }
+}
+
+/// The receiving half of a split MessageBuilder.
+#[derive(Debug)]
+pub struct MessageReceiver {
+    cipher: CipherState,
+    suite: NoiseSuite,
+}
+
+impl MessageReceiver {
+    pub fn decrypt_message_header(&mut self, message: &[u8]) -> Result<u32, ReceiveMessageError> {
+        decrypt_message_header(&mut self.cipher, &self.suite, message)
+    }
+
+    pub fn decrypt_message(&mut self, message: &[u8]) -> Result<Vec<u8>, ReceiveMessageError> {
+        decrypt_message(&mut self.cipher, message)
+    }
+}
+
+// Each direction of a link has its own cipher state, so that the
+// halves of a split builder never share one. A nonce only advances
+// once a message has been processed.
+
+fn encrypt_message(cipher: &mut CipherState, message: &[u8]) -> Result<Vec<u8>, SendMessageError> {
+    let ct_len = MAC_SIZE + message.len();
+    if ct_len > NOISE_MESSAGE_MAX_SIZE {
+        return Err(SendMessageError::InvalidMessageSize);
+    }
+    let mut ct_hdr = [0u8; 4];
+    BigEndian::write_u32(&mut ct_hdr, ct_len as u32);
+    let mut ciphertext_header = [0u8; 4 + MAC_SIZE];
+    let _header_len = cipher.encrypt(&ct_hdr, &mut ciphertext_header);
+    let mut ciphertext = vec![0u8; ct_len];
+    let _payload_len = cipher.encrypt(message, &mut ciphertext);
+    let mut output = Vec::new();
+    output.extend_from_slice(&ciphertext_header[.._header_len]);
+    output.extend_from_slice(&ciphertext[.._payload_len]);
+    ct_hdr.zeroize();
+    Ok(output)
+}
+
+fn decrypt_message_header(cipher: &mut CipherState, suite: &NoiseSuite, message: &[u8]) -> Result<u32, ReceiveMessageError> {
+    let mut header = [0u8; 4];
+    let header_size = suite.message_header_size();
+    if message.len() < header_size {
+        return Err(ReceiveMessageError::DecryptFail);
+    }
+    match cipher.decrypt(&message[..header_size], &mut header) {
+        Ok(x) => {
+            assert_eq!(x, 4);
+            let ct_len = BigEndian::read_u32(&header[..4]);
+            header[..4].zeroize();
+            Ok(ct_len)
+        },
+        Err(_) => Err(ReceiveMessageError::DecryptFail),
+    }
+}
+
+fn decrypt_message(cipher: &mut CipherState, message: &[u8]) -> Result<Vec<u8>, ReceiveMessageError> {
+    let mut plaintext = [0u8; NOISE_MESSAGE_MAX_SIZE];
+    match cipher.decrypt(message, &mut plaintext) {
+        Ok(_len) => {
+            let body = plaintext[.._len].to_vec();
+            plaintext[.._len].zeroize();
+            Ok(body)
+        },
+        Err(_) => Err(ReceiveMessageError::DecryptFail),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate rand;
@@ -451,6 +1173,11 @@ mod tests {
     use super::super::sphinxcrypto::constants::USER_FORWARD_PAYLOAD_SIZE;
     use super::{PeerAuthenticator, ProviderAuthenticatorState};
     use super::super::commands::Command;
+    use super::super::constants::{SUPPORTED_PROTOCOL_VERSIONS, NOISE_MESSAGE_HEADER_SIZE,
+                                  NOISE_HANDSHAKE_MESSAGE2_SIZE, NOISE_HANDSHAKE_MESSAGE3_SIZE};
+    use super::super::suite::{NoiseCipher, NoiseHash};
+    use super::super::clock::ManualClock;
+    use super::super::rng::DeterministicRng;
     use super::*;
 
     #[test]
@@ -458,8 +1185,20 @@ mod tests {
         let auth1 = AuthenticateMessage{
             ad: vec![1,2,3],
             unix_time: 321,
+            version: None,
+        };
+        let raw = auth1.to_vec().unwrap();
+        assert_eq!(raw.len(), AUTH_MESSAGE_SIZE);
+        let auth2 = AuthenticateMessage::from_bytes(&raw).unwrap();
+        assert_eq!(auth1, auth2);
+
+        let auth1 = AuthenticateMessage{
+            ad: vec![1,2,3],
+            unix_time: 321,
+            version: Some(1),
         };
         let raw = auth1.to_vec().unwrap();
+        assert_eq!(raw.len(), VERSIONED_AUTH_MESSAGE_SIZE);
         let auth2 = AuthenticateMessage::from_bytes(&raw).unwrap();
         assert_eq!(auth1, auth2);
     }
@@ -472,26 +1211,42 @@ mod tests {
         let client_keypair = PrivateKey::generate(&mut r).unwrap();
 
         // server
-        let mut provider_auth = ProviderAuthenticatorState::default();
-        provider_auth.client_map.insert(client_keypair.public_key(), true);
+        let provider_auth = ProviderAuthenticatorState::default();
+        provider_auth.client_map.insert(client_keypair.public_key());
         let provider_authenticator = PeerAuthenticator::Provider(provider_auth);
         let server_config = SessionConfig {
             authenticator: provider_authenticator,
-            authentication_key: server_keypair.clone(),
+            authentication_key: server_keypair.clone().into(),
             peer_public_key: None,
             additional_data: vec![],
+            replay_cache: None,
+            protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            noise_suite: NoiseSuite::default(),
+            psk: None,
+            clock: None,
+            rng: None,
+            rate_limits: RateLimits::default(),
+            metrics: None,
         };
         let mut server_session = MessageBuilder::new(server_config, false).unwrap();
 
         // client
         let mut client_auth = ClientAuthenticatorState::default();
-        client_auth.peer_public_key = server_keypair.public_key();
+        client_auth.peer_public_keys = vec![server_keypair.public_key()];
         let client_authenticator = PeerAuthenticator::Client(client_auth);
         let client_config = SessionConfig {
             authenticator: client_authenticator,
-            authentication_key: client_keypair,
+            authentication_key: client_keypair.into(),
             peer_public_key: Some(server_keypair.public_key()),
             additional_data: vec![],
+            replay_cache: None,
+            protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            noise_suite: NoiseSuite::default(),
+            psk: None,
+            clock: None,
+            rng: None,
+            rate_limits: RateLimits::default(),
+            metrics: None,
         };
         let mut client_session = MessageBuilder::new(client_config, true).unwrap();
 
@@ -499,16 +1254,16 @@ mod tests {
         // c -> s
         let client_handshake1 = client_session.client_handshake1().unwrap();
         let _ok = client_session.sent_client_handshake1();
-        let server_handshake1 = server_session.received_client_handshake1(client_handshake1).unwrap();
+        let server_handshake1 = server_session.received_client_handshake1(&client_handshake1).unwrap();
 
         // s -> c
         server_session.sent_server_handshake1();
-        client_session.received_server_handshake1(server_handshake1).unwrap();
+        client_session.received_server_handshake1(&server_handshake1).unwrap();
 
         // c -> s
         let client_handshake2 = client_session.client_handshake2().unwrap();
         client_session.sent_client_handshake2();
-        server_session.received_client_handshake2(client_handshake2).unwrap();
+        server_session.received_client_handshake2(&client_handshake2).unwrap();
 
         // data transfer phase
         server_session = server_session.into_transport_mode().unwrap();
@@ -534,4 +1289,383 @@ mod tests {
         let raw_cmd = server_session.decrypt_message(&client_to_send[NOISE_MESSAGE_HEADER_SIZE..].to_vec()).unwrap();
         assert_eq!(raw_cmd, client_message);
     }
+
+    #[test]
+    fn peer_credentials_test() {
+        let mut r = OsRng::new().expect("failure to create an OS RNG");
+        let peer_key = PrivateKey::generate(&mut r).unwrap().public_key();
+
+        let creds = PeerCredentials {
+            additional_data: vec![0xff, 0xfe],
+            public_key: peer_key.clone(),
+        };
+        assert!(creds.username().is_err());
+        assert!(creds.node_identity().is_err());
+        assert!(AdditionalDataPolicy::Any.is_valid(&creds));
+        assert!(!AdditionalDataPolicy::Username.is_valid(&creds));
+
+        let creds = PeerCredentials {
+            additional_data: vec![7u8; NODE_IDENTITY_SIZE],
+            public_key: peer_key.clone(),
+        };
+        assert_eq!(creds.node_identity().unwrap(), [7u8; NODE_IDENTITY_SIZE]);
+
+        let mut server_auth = ServerAuthenticatorState::default();
+        server_auth.mix_map.insert(peer_key.clone());
+        server_auth.mix_ad_policy = AdditionalDataPolicy::NodeIdentity;
+        let mut authenticator = PeerAuthenticator::Server(server_auth);
+        assert!(authenticator.is_peer_valid(&creds));
+
+        let creds = PeerCredentials {
+            additional_data: b"alice".to_vec(),
+            public_key: peer_key,
+        };
+        assert!(!authenticator.is_peer_valid(&creds));
+    }
+
+    #[test]
+    fn username_handshake_test() {
+        let (mut client_config, mut server_config) = test_configs();
+        let mut provider_auth = ProviderAuthenticatorState::default();
+        provider_auth.client_map.insert(client_config.authentication_key.public_key());
+        provider_auth.client_ad_policy = AdditionalDataPolicy::Username;
+        server_config.authenticator = PeerAuthenticator::Provider(provider_auth);
+        client_config.additional_data = b"alice".to_vec();
+        let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+        complete_handshake(&mut client_session, &mut server_session);
+
+        server_session = server_session.into_transport_mode().unwrap();
+        assert_eq!(server_session.peer_credentials().username().unwrap(), "alice");
+        assert!(server_session.authenticator.is_peer_client());
+    }
+
+    #[test]
+    fn replay_test() {
+        let mut r = OsRng::new().expect("failure to create an OS RNG");
+        let server_keypair = PrivateKey::generate(&mut r).unwrap();
+        let client_keypair = PrivateKey::generate(&mut r).unwrap();
+        let cache = ReplayCache::new(4, 120);
+
+        let provider_auth = ProviderAuthenticatorState::default();
+        provider_auth.client_map.insert(client_keypair.public_key());
+        let mut server_config = SessionConfig::test_config(PeerAuthenticator::Provider(provider_auth), server_keypair.clone(), None);
+        server_config.replay_cache = Some(cache.clone());
+
+        let mut client_auth = ClientAuthenticatorState::default();
+        client_auth.peer_public_keys = vec![server_keypair.public_key()];
+        let client_config = SessionConfig::test_config(PeerAuthenticator::Client(client_auth), client_keypair, Some(server_keypair.public_key()));
+        let mut client_session = MessageBuilder::new(client_config, true).unwrap();
+        let client_handshake1 = client_session.client_handshake1().unwrap();
+        client_session.sent_client_handshake1();
+
+        // A flood of junk first messages from anonymous senders is
+        // never recorded, so it cannot fill the cache.
+        for i in 0..16u8 {
+            let mut junk_session = MessageBuilder::new(server_config.clone(), false).unwrap();
+            let mut junk = vec![i; client_handshake1.len()];
+            junk[0] = client_handshake1[0];
+            let _ = junk_session.received_client_handshake1(&junk);
+        }
+        assert!(cache.is_empty());
+
+        // The valid peer still gets through, clients send no timestamp.
+        let mut server_session = MessageBuilder::new(server_config.clone(), false).unwrap();
+        let server_handshake1 = server_session.received_client_handshake1(&client_handshake1).unwrap();
+        server_session.sent_server_handshake1();
+        client_session.received_server_handshake1(&server_handshake1).unwrap();
+        let client_handshake2 = client_session.client_handshake2().unwrap();
+        server_session.received_client_handshake2(&client_handshake2).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        // A second responder sharing the cache rejects the replay.
+        let mut replay_session = MessageBuilder::new(server_config, false).unwrap();
+        match replay_session.received_client_handshake1(&client_handshake1) {
+            Err(ServerHandshakeError::ReplayError) => {},
+            _ => panic!("expected a replay error"),
+        }
+    }
+
+    fn test_configs() -> (SessionConfig, SessionConfig) {
+        let mut r = OsRng::new().expect("failure to create an OS RNG");
+        let server_keypair = PrivateKey::generate(&mut r).unwrap();
+        let client_keypair = PrivateKey::generate(&mut r).unwrap();
+
+        let server_auth = ServerAuthenticatorState::default();
+        server_auth.mix_map.insert(client_keypair.public_key());
+        let server_config = SessionConfig::test_config(PeerAuthenticator::Server(server_auth), server_keypair.clone(), None);
+
+        let client_auth = ServerAuthenticatorState::default();
+        client_auth.mix_map.insert(server_keypair.public_key());
+        let client_config = SessionConfig::test_config(PeerAuthenticator::Server(client_auth), client_keypair, Some(server_keypair.public_key()));
+        (client_config, server_config)
+    }
+
+    fn test_sessions(client_config: SessionConfig, server_config: SessionConfig) -> (MessageBuilder, MessageBuilder) {
+        let client_session = MessageBuilder::new(client_config, true).unwrap();
+        let server_session = MessageBuilder::new(server_config, false).unwrap();
+        (client_session, server_session)
+    }
+
+    fn versioned_handshake(client_versions: Vec<u8>, server_versions: Vec<u8>) -> Result<(u8, u8), ServerHandshakeError> {
+        let (mut client_config, mut server_config) = test_configs();
+        client_config.protocol_versions = client_versions;
+        server_config.protocol_versions = server_versions;
+        let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+        let client_handshake1 = client_session.client_handshake1().unwrap();
+        client_session.sent_client_handshake1();
+        let server_handshake1 = server_session.received_client_handshake1(&client_handshake1)?;
+        server_session.sent_server_handshake1();
+        client_session.received_server_handshake1(&server_handshake1).unwrap();
+        let client_handshake2 = client_session.client_handshake2().unwrap();
+        client_session.sent_client_handshake2();
+        server_session.received_client_handshake2(&client_handshake2)?;
+        Ok((client_session.protocol_version().unwrap(), server_session.protocol_version().unwrap()))
+    }
+
+    #[test]
+    fn protocol_version_test() {
+        assert_eq!(versioned_handshake(vec![0], vec![0]).unwrap(), (0, 0));
+        assert_eq!(versioned_handshake(vec![0, 1], vec![0]).unwrap(), (0, 0));
+        assert_eq!(versioned_handshake(vec![0, 2], vec![0, 1, 2]).unwrap(), (2, 2));
+        match versioned_handshake(vec![1], vec![0]) {
+            Err(ServerHandshakeError::NoCommonVersionError) => {},
+            _ => panic!("expected no common version"),
+        }
+        match versioned_handshake(vec![0], vec![1]) {
+            Err(ServerHandshakeError::PrologueMismatchError) => {},
+            _ => panic!("expected a prologue mismatch"),
+        }
+
+        // Version 0 on its own uses the legacy wire format, even with
+        // a responder that supports newer versions.
+        let (client_config, mut server_config) = test_configs();
+        server_config.protocol_versions = vec![0, 1];
+        let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+        let client_handshake1 = client_session.client_handshake1().unwrap();
+        assert_eq!(client_handshake1[..PROLOGUE_SIZE], PROLOGUE);
+        client_session.sent_client_handshake1();
+        let server_handshake1 = server_session.received_client_handshake1(&client_handshake1).unwrap();
+        assert_eq!(server_handshake1.len(), NOISE_HANDSHAKE_MESSAGE2_SIZE);
+        server_session.sent_server_handshake1();
+        client_session.received_server_handshake1(&server_handshake1).unwrap();
+        let client_handshake2 = client_session.client_handshake2().unwrap();
+        assert_eq!(client_handshake2.len(), NOISE_HANDSHAKE_MESSAGE3_SIZE);
+        server_session.received_client_handshake2(&client_handshake2).unwrap();
+        assert_eq!(server_session.protocol_version(), Some(0));
+
+        let mut r = OsRng::new().expect("failure to create an OS RNG");
+        let mut config = SessionConfig::test_config(PeerAuthenticator::Server(ServerAuthenticatorState::default()), PrivateKey::generate(&mut r).unwrap(), None);
+        config.protocol_versions = vec![];
+        assert!(MessageBuilder::new(config, false).is_err());
+    }
+
+    fn complete_handshake(client_session: &mut MessageBuilder, server_session: &mut MessageBuilder) {
+        let client_handshake1 = client_session.client_handshake1().unwrap();
+        client_session.sent_client_handshake1();
+        let server_handshake1 = server_session.received_client_handshake1(&client_handshake1).unwrap();
+        server_session.sent_server_handshake1();
+        client_session.received_server_handshake1(&server_handshake1).unwrap();
+        let client_handshake2 = client_session.client_handshake2().unwrap();
+        client_session.sent_client_handshake2();
+        server_session.received_client_handshake2(&client_handshake2).unwrap();
+    }
+
+    #[test]
+    fn noise_suite_test() {
+        let suite = NoiseSuite {
+            cipher: NoiseCipher::AesGcm,
+            hash: NoiseHash::Sha256,
+        };
+        let (mut client_config, mut server_config) = test_configs();
+        client_config.noise_suite = suite;
+        server_config.noise_suite = suite;
+        let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+        complete_handshake(&mut client_session, &mut server_session);
+
+        let mut client_session = client_session.into_transport_mode().unwrap();
+        let mut server_session = server_session.into_transport_mode().unwrap();
+        let to_send = client_session.encrypt_message(b"hello").unwrap();
+        let _len = server_session.decrypt_message_header(&to_send).unwrap();
+        let header_size = suite.message_header_size();
+        assert_eq!(server_session.decrypt_message(&to_send[header_size..]).unwrap(), b"hello".to_vec());
+
+        // Peers configured with different suites cannot complete the handshake.
+        let (mut client_session, mut server_session) = {
+            let (client_config, mut server_config) = test_configs();
+            server_config.noise_suite = suite;
+            test_sessions(client_config, server_config)
+        };
+        let client_handshake1 = client_session.client_handshake1().unwrap();
+        client_session.sent_client_handshake1();
+        let server_handshake1 = server_session.received_client_handshake1(&client_handshake1).unwrap();
+        match client_session.received_server_handshake1(&server_handshake1) {
+            Err(ClientHandshakeError::DecryptError) => {},
+            _ => panic!("expected a decryption failure"),
+        }
+    }
+
+    #[test]
+    fn psk_test() {
+        let psk = [3u8; PSK_SIZE];
+        let (mut client_config, mut server_config) = test_configs();
+        client_config.psk = Some(Zeroizing::new(psk));
+        server_config.psk = Some(Zeroizing::new(psk));
+        let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+        complete_handshake(&mut client_session, &mut server_session);
+
+        // Different keys are only detected by the responder.
+        let (mut client_config, mut server_config) = test_configs();
+        client_config.psk = Some(Zeroizing::new([4u8; PSK_SIZE]));
+        server_config.psk = Some(Zeroizing::new(psk));
+        let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+        let client_handshake1 = client_session.client_handshake1().unwrap();
+        client_session.sent_client_handshake1();
+        let server_handshake1 = server_session.received_client_handshake1(&client_handshake1).unwrap();
+        server_session.sent_server_handshake1();
+        client_session.received_server_handshake1(&server_handshake1).unwrap();
+        let client_handshake2 = client_session.client_handshake2().unwrap();
+        match server_session.received_client_handshake2(&client_handshake2) {
+            Err(ServerHandshakeError::DecryptError) => {},
+            _ => panic!("expected a decryption failure"),
+        }
+
+        // A responder without a psk uses a different pattern, it
+        // reads a shorter first message and the initiator then fails
+        // to decrypt the second.
+        let (mut client_config, server_config) = test_configs();
+        client_config.psk = Some(Zeroizing::new(psk));
+        let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+        let client_handshake1 = client_session.client_handshake1().unwrap();
+        client_session.sent_client_handshake1();
+        let message1_size = server_session.handshake_message1_size();
+        let server_handshake1 = server_session.received_client_handshake1(&client_handshake1[..message1_size]).unwrap();
+        match client_session.received_server_handshake1(&server_handshake1) {
+            Err(ClientHandshakeError::DecryptError) => {},
+            _ => panic!("expected a decryption failure"),
+        }
+    }
+
+    #[test]
+    fn exporter_test() {
+        let (client_config, server_config) = test_configs();
+        let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+        assert!(client_session.handshake_hash().is_none());
+        match client_session.export_keying_material(b"label", b"", 32) {
+            Err(ExportError::HandshakeIncompleteError) => {},
+            _ => panic!("expected an incomplete handshake"),
+        }
+        complete_handshake(&mut client_session, &mut server_session);
+        let client_session = client_session.into_transport_mode().unwrap();
+        let server_session = server_session.into_transport_mode().unwrap();
+
+        assert_eq!(client_session.handshake_hash().unwrap().len(), 64);
+        assert_eq!(client_session.handshake_hash(), server_session.handshake_hash());
+        let client_key = client_session.export_keying_material(b"registration", b"alice", 100).unwrap();
+        let server_key = server_session.export_keying_material(b"registration", b"alice", 100).unwrap();
+        assert_eq!(client_key.len(), 100);
+        assert_eq!(client_key, server_key);
+        assert_ne!(client_key, server_session.export_keying_material(b"registration", b"bob", 100).unwrap());
+        assert!(server_session.export_keying_material(b"registration", b"", 0).is_err());
+
+        // An observer of the handshake knows its hash but not the
+        // transport keys, so it cannot derive the same bytes.
+        let handshake_hash = client_session.handshake_hash().unwrap();
+        let observed = export_keying_material(handshake_hash, b"registration", b"alice", 100).unwrap();
+        assert_ne!(client_key, observed);
+    }
+
+    #[test]
+    fn redacted_debug_test() {
+        let mut r = OsRng::new().expect("failure to create an OS RNG");
+        let key = PrivateKey::generate(&mut r).unwrap();
+        let key_debug = format!("{:?}", key);
+        let mut config = SessionConfig::test_config(PeerAuthenticator::Server(ServerAuthenticatorState::default()), key, None);
+        config.psk = Some(Zeroizing::new([9u8; PSK_SIZE]));
+        let config_debug = format!("{:?}", config);
+        assert!(!config_debug.contains(&key_debug));
+        assert!(!config_debug.contains("9, 9, 9"));
+
+        let builder = MessageBuilder::new(config, false).unwrap();
+        assert!(!format!("{:?}", builder).contains(&key_debug));
+
+        let mut key = PrivateKey::generate(&mut r).unwrap();
+        wipe_private_key(&mut key);
+        assert_eq!(key.to_vec(), vec![0u8; KEY_SIZE]);
+
+        let alice = AuthenticationKey::from(PrivateKey::generate(&mut r).unwrap());
+        let bob = AuthenticationKey::from(PrivateKey::generate(&mut r).unwrap());
+        assert_eq!(*alice.exchange(&bob.public_key()), *bob.exchange(&alice.public_key()));
+    }
+
+    #[test]
+    fn clock_test() {
+        // Mix peers send timestamps which the responder checks
+        // against its own clock when a replay cache is configured.
+        let client_clock = ManualClock::new(1_000_000);
+        let server_clock = ManualClock::new(1_000_030);
+        let (mut client_config, mut server_config) = test_configs();
+        client_config.clock = Some(Arc::new(client_clock.clone()));
+        server_config.clock = Some(Arc::new(server_clock.clone()));
+        server_config.replay_cache = Some(ReplayCache::new(16, 60));
+        let (mut client_session, mut server_session) = test_sessions(client_config.clone(), server_config.clone());
+        complete_handshake(&mut client_session, &mut server_session);
+        assert_eq!(client_session.clock_skew(), 30);
+
+        server_clock.advance(100);
+        let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+        let client_handshake1 = client_session.client_handshake1().unwrap();
+        client_session.sent_client_handshake1();
+        let server_handshake1 = server_session.received_client_handshake1(&client_handshake1).unwrap();
+        server_session.sent_server_handshake1();
+        client_session.received_server_handshake1(&server_handshake1).unwrap();
+        let client_handshake2 = client_session.client_handshake2().unwrap();
+        match server_session.received_client_handshake2(&client_handshake2) {
+            Err(ServerHandshakeError::StaleTimestampError) => {},
+            _ => panic!("expected a stale timestamp"),
+        }
+
+        // Mixes which predate timestamps send zero, which is accepted
+        // at version 0 but not from peers speaking a newer version.
+        let (mut client_config, mut server_config) = test_configs();
+        client_config.clock = Some(Arc::new(ManualClock::new(0)));
+        server_config.clock = Some(Arc::new(server_clock));
+        server_config.replay_cache = Some(ReplayCache::new(16, 60));
+        server_config.protocol_versions = vec![0, 1];
+        let (mut client_session, mut server_session) = test_sessions(client_config.clone(), server_config.clone());
+        complete_handshake(&mut client_session, &mut server_session);
+        client_config.protocol_versions = vec![0, 1];
+        let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+        let client_handshake1 = client_session.client_handshake1().unwrap();
+        client_session.sent_client_handshake1();
+        let server_handshake1 = server_session.received_client_handshake1(&client_handshake1).unwrap();
+        server_session.sent_server_handshake1();
+        client_session.received_server_handshake1(&server_handshake1).unwrap();
+        let client_handshake2 = client_session.client_handshake2().unwrap();
+        match server_session.received_client_handshake2(&client_handshake2) {
+            Err(ServerHandshakeError::StaleTimestampError) => {},
+            _ => panic!("expected a stale timestamp"),
+        }
+    }
+
+    #[test]
+    fn deterministic_transcript_test() {
+        let (mut client_config, mut server_config) = test_configs();
+        client_config.clock = Some(Arc::new(ManualClock::new(1_000_000)));
+        server_config.clock = Some(Arc::new(ManualClock::new(1_000_000)));
+        let transcript = || {
+            let mut client_config = client_config.clone();
+            let mut server_config = server_config.clone();
+            client_config.rng = Some(Arc::new(DeterministicRng::new([1u8; 32])));
+            server_config.rng = Some(Arc::new(DeterministicRng::new([2u8; 32])));
+            let (mut client_session, mut server_session) = test_sessions(client_config, server_config);
+            let client_handshake1 = client_session.client_handshake1().unwrap();
+            client_session.sent_client_handshake1();
+            let server_handshake1 = server_session.received_client_handshake1(&client_handshake1).unwrap();
+            server_session.sent_server_handshake1();
+            client_session.received_server_handshake1(&server_handshake1).unwrap();
+            let client_handshake2 = client_session.client_handshake2().unwrap();
+            vec![client_handshake1, server_handshake1, client_handshake2]
+        };
+        assert_eq!(transcript(), transcript());
+    }
 }
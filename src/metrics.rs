@@ -0,0 +1,111 @@
+// metrics.rs - session metrics
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use super::errors::{AdmissionError, HandshakeError};
+
+
+/// Metrics receives the events of a session. Sessions sharing a
+/// Metrics implementation are counted together, give each session
+/// its own to count them separately.
+pub trait Metrics: Send + Sync + fmt::Debug {
+    fn handshake_completed(&self, is_initiator: bool);
+
+    fn handshake_failed(&self, is_initiator: bool, error: &HandshakeError);
+
+    /// Called with the name of the command, see `Command::name`.
+    fn command_sent(&self, command: &'static str);
+
+    fn command_received(&self, command: &'static str);
+
+    fn bytes_sent(&self, count: usize);
+
+    fn bytes_received(&self, count: usize);
+
+    fn decrypt_failed(&self);
+
+    /// Called by a `Listener` for each connection refused by
+    /// admission control, before any handshake is attempted.
+    fn connection_rejected(&self, error: &AdmissionError);
+}
+
+/// A snapshot of the counters of `InMemoryMetrics`. Failed
+/// handshakes and rejected connections are counted by error variant.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub handshakes_completed: u64,
+    pub handshakes_failed: HashMap<&'static str, u64>,
+    pub commands_sent: HashMap<&'static str, u64>,
+    pub commands_received: HashMap<&'static str, u64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub decrypt_failures: u64,
+    pub connections_rejected: HashMap<&'static str, u64>,
+}
+
+/// InMemoryMetrics counts events in memory. Clones share the same
+/// counters.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryMetrics {
+    counters: Arc<Mutex<MetricsSnapshot>>,
+}
+
+impl InMemoryMetrics {
+    pub fn new() -> InMemoryMetrics {
+        InMemoryMetrics::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.counters.lock().unwrap().clone()
+    }
+}
+
+impl Metrics for InMemoryMetrics {
+    fn handshake_completed(&self, _is_initiator: bool) {
+        self.counters.lock().unwrap().handshakes_completed += 1;
+    }
+
+    fn handshake_failed(&self, _is_initiator: bool, error: &HandshakeError) {
+        *self.counters.lock().unwrap().handshakes_failed.entry(error.name()).or_insert(0) += 1;
+    }
+
+    fn command_sent(&self, command: &'static str) {
+        *self.counters.lock().unwrap().commands_sent.entry(command).or_insert(0) += 1;
+    }
+
+    fn command_received(&self, command: &'static str) {
+        *self.counters.lock().unwrap().commands_received.entry(command).or_insert(0) += 1;
+    }
+
+    fn bytes_sent(&self, count: usize) {
+        self.counters.lock().unwrap().bytes_sent += count as u64;
+    }
+
+    fn bytes_received(&self, count: usize) {
+        self.counters.lock().unwrap().bytes_received += count as u64;
+    }
+
+    fn decrypt_failed(&self) {
+        self.counters.lock().unwrap().decrypt_failures += 1;
+    }
+
+    fn connection_rejected(&self, error: &AdmissionError) {
+        *self.counters.lock().unwrap().connections_rejected.entry(error.name()).or_insert(0) += 1;
+    }
+}
@@ -0,0 +1,384 @@
+// queue.rs - bounded outgoing command queue
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::commands::Command;
+use super::errors::SendMessageError;
+use super::sync::SessionSender;
+use super::transport::Transport;
+
+
+/// What to do with a command sent while the queue is full.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum QueueFullPolicy {
+    /// Wait until the writer thread makes room.
+    Block,
+    /// Discard the oldest queued command to make room.
+    DropOldest,
+    /// Discard the command being sent.
+    DropNewest,
+    /// Return `SendMessageError::QueueFull`.
+    Error,
+}
+
+/// A send queue configuration type.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct QueueConfig {
+    /// The number of commands the queue holds, at least one.
+    pub capacity: usize,
+    pub policy: QueueFullPolicy,
+    /// How long closing the queue waits for the queued commands to
+    /// be written before shutting down the link.
+    pub close_timeout: Duration,
+}
+
+impl Default for QueueConfig {
+    fn default() -> QueueConfig {
+        QueueConfig {
+            capacity: 128,
+            policy: QueueFullPolicy::Block,
+            close_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn validate(&self) -> Result<(), SendMessageError> {
+        if self.capacity == 0 {
+            return Err(SendMessageError::InvalidQueueCapacity)
+        }
+        Ok(())
+    }
+}
+
+struct QueueState {
+    commands: VecDeque<Command>,
+    closed: bool,
+    dropped: u64,
+    writer_done: bool,
+}
+
+struct Queue {
+    config: QueueConfig,
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    writer_done: Condvar,
+}
+
+impl Queue {
+    fn push(&self, cmd: Command) -> Result<(), SendMessageError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return Err(SendMessageError::QueueClosed);
+            }
+            if state.commands.len() < self.config.capacity {
+                break
+            }
+            match self.config.policy {
+                QueueFullPolicy::Block => {
+                    state = self.not_full.wait(state).unwrap();
+                },
+                QueueFullPolicy::DropOldest => {
+                    state.commands.pop_front();
+                    state.dropped += 1;
+                },
+                QueueFullPolicy::DropNewest => {
+                    state.dropped += 1;
+                    return Ok(())
+                },
+                QueueFullPolicy::Error => {
+                    state.dropped += 1;
+                    return Err(SendMessageError::QueueFull);
+                },
+            }
+        }
+        state.commands.push_back(cmd);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Returns None once the queue is closed and drained.
+    fn pop(&self) -> Option<Command> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(cmd) = state.commands.pop_front() {
+                self.not_full.notify_one();
+                return Some(cmd)
+            }
+            if state.closed {
+                return None
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    fn set_writer_done(&self) {
+        self.state.lock().unwrap().writer_done = true;
+        self.writer_done.notify_all();
+    }
+
+    /// Wait until the writer thread has returned, for at most
+    /// `timeout`. Returns false if it is still running.
+    fn wait_for_writer(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while !state.writer_done {
+            let now = Instant::now();
+            if now >= deadline {
+                return false
+            }
+            state = self.writer_done.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+/// QueuedSender puts a bounded queue in front of a SessionSender so
+/// that a slow peer does not block the caller. A writer thread drains
+/// the queue, the queue is closed if writing to the peer fails.
+pub struct QueuedSender {
+    queue: Arc<Queue>,
+    writer: Option<thread::JoinHandle<SessionSender>>,
+    shutdown: Mutex<Box<dyn Transport>>,
+}
+
+impl QueuedSender {
+    pub fn new(mut sender: SessionSender, config: QueueConfig) -> Result<QueuedSender, SendMessageError> {
+        config.validate()?;
+        let shutdown = sender.shutdown_handle()?;
+        let queue = Arc::new(Queue {
+            config,
+            state: Mutex::new(QueueState {
+                commands: VecDeque::with_capacity(config.capacity),
+                closed: false,
+                dropped: 0,
+                writer_done: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            writer_done: Condvar::new(),
+        });
+        let writer_queue = queue.clone();
+        let writer = thread::spawn(move || {
+            while let Some(cmd) = writer_queue.pop() {
+                if sender.send_command(&cmd).is_err() {
+                    writer_queue.close();
+                    break
+                }
+            }
+            writer_queue.set_writer_done();
+            sender
+        });
+        Ok(QueuedSender {
+            queue,
+            writer: Some(writer),
+            shutdown: Mutex::new(shutdown),
+        })
+    }
+
+    /// Queue a command, applying the full queue policy if there is
+    /// no room for it.
+    pub fn send_command(&self, cmd: Command) -> Result<(), SendMessageError> {
+        self.queue.push(cmd)
+    }
+
+    /// Returns the number of commands discarded because the queue was full.
+    pub fn dropped_commands(&self) -> u64 {
+        self.queue.state.lock().unwrap().dropped
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.state.lock().unwrap().commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stop accepting commands, wait for the queued ones to be written
+    /// and return the sender. If they are not written within the close
+    /// timeout the link is shut down, discarding the rest. Fails if
+    /// the writer thread panicked, taking the sender with it.
+    pub fn close(mut self) -> Result<SessionSender, SendMessageError> {
+        match self.stop_writer() {
+            Some(result) => result,
+            None => Err(SendMessageError::QueueClosed),
+        }
+    }
+
+    /// Returns None if the writer was already stopped.
+    fn stop_writer(&mut self) -> Option<Result<SessionSender, SendMessageError>> {
+        let writer = self.writer.take()?;
+        self.queue.close();
+        if !self.queue.wait_for_writer(self.queue.config.close_timeout) {
+            // The writer is blocked on a peer which stopped reading.
+            let _ = self.shutdown.lock().unwrap().shutdown_transport();
+        }
+        Some(writer.join().map_err(|_| SendMessageError::WriterPanicked))
+    }
+}
+
+impl Drop for QueuedSender {
+    fn drop(&mut self) {
+        let _ = self.stop_writer();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::sync::Session;
+    use super::super::sync::tests::test_configs;
+    use super::super::transport::{simulated_duplex, NetworkConditions};
+
+    fn test_queue(capacity: usize, policy: QueueFullPolicy) -> Queue {
+        Queue {
+            config: QueueConfig { capacity, policy, ..QueueConfig::default() },
+            state: Mutex::new(QueueState {
+                commands: VecDeque::new(),
+                closed: false,
+                dropped: 0,
+                writer_done: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            writer_done: Condvar::new(),
+        }
+    }
+
+    fn message(sequence: u32) -> Command {
+        Command::MessageMessage {
+            queue_size_hint: 0,
+            sequence,
+            payload: vec![],
+        }
+    }
+
+    fn sequence(cmd: Option<Command>) -> u32 {
+        match cmd {
+            Some(Command::MessageMessage { sequence, .. }) => sequence,
+            _ => panic!("unexpected command"),
+        }
+    }
+
+    #[test]
+    fn queue_policy_test() {
+        let queue = test_queue(2, QueueFullPolicy::DropOldest);
+        for i in 0..3 {
+            queue.push(message(i)).unwrap();
+        }
+        assert_eq!(sequence(queue.pop()), 1);
+        assert_eq!(queue.state.lock().unwrap().dropped, 1);
+
+        let queue = test_queue(2, QueueFullPolicy::DropNewest);
+        for i in 0..3 {
+            queue.push(message(i)).unwrap();
+        }
+        assert_eq!(sequence(queue.pop()), 0);
+        assert_eq!(sequence(queue.pop()), 1);
+
+        let queue = test_queue(1, QueueFullPolicy::Error);
+        queue.push(message(0)).unwrap();
+        match queue.push(message(1)) {
+            Err(SendMessageError::QueueFull) => {},
+            _ => panic!("expected a full queue"),
+        }
+
+        let queue = Arc::new(test_queue(1, QueueFullPolicy::Block));
+        queue.push(message(0)).unwrap();
+        let pusher = queue.clone();
+        let blocked = thread::spawn(move || pusher.push(message(1)));
+        assert_eq!(sequence(queue.pop()), 0);
+        blocked.join().unwrap().unwrap();
+        assert_eq!(sequence(queue.pop()), 1);
+        queue.close();
+        assert!(queue.pop().is_none());
+        match queue.push(message(2)) {
+            Err(SendMessageError::QueueClosed) => {},
+            _ => panic!("expected a closed queue"),
+        }
+    }
+
+    #[test]
+    fn queued_sender_test() {
+        let config = QueueConfig {
+            capacity: 0,
+            ..QueueConfig::default()
+        };
+        match config.validate() {
+            Err(SendMessageError::InvalidQueueCapacity) => {},
+            _ => panic!("expected an invalid capacity"),
+        }
+
+        // The link stops accepting writes a little after the handshake,
+        // the queue fills once the writer is stuck.
+        let (client_config, server_config) = test_configs();
+        let conditions = NetworkConditions {
+            stall_after: Some(2048),
+            ..NetworkConditions::default()
+        };
+        let (client_stream, server_stream) = simulated_duplex(conditions, NetworkConditions::default());
+        let server = thread::spawn(move|| {
+            let mut session = Session::new(server_config, false).unwrap();
+            session.initialize(server_stream).unwrap();
+            session = session.into_transport_mode().unwrap();
+            session.finalize_handshake().unwrap();
+            let (_, mut receiver) = session.split().unwrap();
+            (receiver.recv_command().unwrap(), receiver)
+        });
+        let mut session = Session::new(client_config, true).unwrap();
+        session.initialize(client_stream).unwrap();
+        session = session.into_transport_mode().unwrap();
+        session.finalize_handshake().unwrap();
+        let (sender, _receiver) = session.split().unwrap();
+
+        let config = QueueConfig {
+            capacity: 4,
+            policy: QueueFullPolicy::Error,
+            close_timeout: Duration::from_millis(100),
+        };
+        let queued = QueuedSender::new(sender, config).unwrap();
+        for epoch in 0..1000 {
+            if queued.send_command(Command::GetConsensus { epoch }).is_err() {
+                break
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(queued.dropped_commands(), 1);
+        let (cmd, _server_receiver) = server.join().unwrap();
+        assert_eq!(cmd, Command::GetConsensus { epoch: 0 });
+
+        // Closing gives up on the stuck writer after the timeout and
+        // shuts the link down.
+        let start = Instant::now();
+        let mut sender = queued.close().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(sender.send_command(&Command::NoOp {}).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+// rate_limit.rs - token bucket rate limiting of incoming commands
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::time::{Duration, Instant};
+
+use super::commands::Command;
+use super::errors::HandshakeError;
+
+
+/// A token bucket configuration, `rate` tokens are added per second
+/// up to a maximum of `burst` tokens. Each command costs one token.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct TokenBucketConfig {
+    pub rate: u32,
+    pub burst: u32,
+}
+
+impl TokenBucketConfig {
+    fn is_valid(&self) -> bool {
+        self.rate > 0 && self.burst > 0
+    }
+}
+
+/// What to do with a command received after its budget is exhausted.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RateLimitPolicy {
+    /// Stop reading from the peer until the command is within budget.
+    Delay,
+    /// Silently discard the command.
+    Drop,
+    /// Close the session.
+    Disconnect,
+}
+
+/// A rate limit configuration type. Packet commands (`SendPacket`
+/// and the `Message*` commands) and control commands have separate
+/// budgets so that a peer flooding packets cannot starve the other.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    pub packets: TokenBucketConfig,
+    pub control: TokenBucketConfig,
+    pub policy: RateLimitPolicy,
+}
+
+impl RateLimitConfig {
+    /// A budget which never refills would make `Delay` wait forever,
+    /// so both buckets need a non-zero rate and burst.
+    pub fn validate(&self) -> Result<(), HandshakeError> {
+        if !self.packets.is_valid() || !self.control.is_valid() {
+            return Err(HandshakeError::InvalidRateLimitError)
+        }
+        Ok(())
+    }
+}
+
+/// Rate limits by peer role, no limit is applied to a role without
+/// a configuration.
+///
+/// The budgets belong to a session, a peer with several links to us
+/// gets a budget on each of them. Limit the links a peer may open
+/// with admission control.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct RateLimits {
+    pub client: Option<RateLimitConfig>,
+    pub mix: Option<RateLimitConfig>,
+}
+
+impl RateLimits {
+    pub fn validate(&self) -> Result<(), HandshakeError> {
+        for config in self.client.iter().chain(self.mix.iter()) {
+            config.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn for_peer(&self, is_client: bool) -> Option<RateLimitConfig> {
+        if is_client {
+            self.client
+        } else {
+            self.mix
+        }
+    }
+}
+
+/// TokenBucket is a single budget, time is passed in by the caller.
+pub struct TokenBucket {
+    config: TokenBucketConfig,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(config: TokenBucketConfig, now: Instant) -> TokenBucket {
+        TokenBucket {
+            config,
+            tokens: f64::from(config.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            let elapsed = now - self.updated;
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            let tokens = self.tokens + elapsed * f64::from(self.config.rate);
+            self.tokens = tokens.min(f64::from(self.config.burst));
+            self.updated = now;
+        }
+    }
+
+    /// Take a token if one is available.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true
+        }
+        false
+    }
+
+    /// Returns true if the bucket has refilled completely.
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= f64::from(self.config.burst)
+    }
+
+    /// Take a token unconditionally, returning how long to wait
+    /// until it would have been available. Returns None if the
+    /// bucket never refills.
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            return Some(Duration::from_secs(0))
+        }
+        if self.config.rate == 0 {
+            return None
+        }
+        let wait = -self.tokens / f64::from(self.config.rate);
+        Some(Duration::new(wait as u64, (wait.fract() * 1e9) as u32))
+    }
+}
+
+/// The outcome of checking a command against its budget.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Verdict {
+    Accept,
+    Delay(Duration),
+    Drop,
+    Disconnect,
+}
+
+/// RateLimiter tracks the budgets of the commands received from one peer.
+pub struct RateLimiter {
+    policy: RateLimitPolicy,
+    packets: TokenBucket,
+    control: TokenBucket,
+    dropped: u64,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        let now = Instant::now();
+        RateLimiter {
+            policy: config.policy,
+            packets: TokenBucket::new(config.packets, now),
+            control: TokenBucket::new(config.control, now),
+            dropped: 0,
+        }
+    }
+
+    pub fn check(&mut self, cmd: &Command) -> Verdict {
+        self.check_at(cmd, Instant::now())
+    }
+
+    fn check_at(&mut self, cmd: &Command, now: Instant) -> Verdict {
+        let bucket = if is_packet_command(cmd) {
+            &mut self.packets
+        } else {
+            &mut self.control
+        };
+        if self.policy == RateLimitPolicy::Delay {
+            match bucket.take(now) {
+                Some(wait) if wait == Duration::from_secs(0) => return Verdict::Accept,
+                Some(wait) => return Verdict::Delay(wait),
+                None => {
+                    self.dropped += 1;
+                    return Verdict::Disconnect
+                },
+            }
+        }
+        if bucket.try_take(now) {
+            return Verdict::Accept
+        }
+        self.dropped += 1;
+        if self.policy == RateLimitPolicy::Drop {
+            return Verdict::Drop
+        }
+        Verdict::Disconnect
+    }
+
+    /// Returns the number of commands discarded or rejected for
+    /// exceeding their budget.
+    pub fn dropped_commands(&self) -> u64 {
+        self.dropped
+    }
+}
+
+fn is_packet_command(cmd: &Command) -> bool {
+    match *cmd {
+        Command::SendPacket{..} |
+        Command::MessageAck{..} |
+        Command::MessageMessage{..} |
+        Command::MessageEmpty{..} => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(policy: RateLimitPolicy) -> RateLimitConfig {
+        RateLimitConfig {
+            packets: TokenBucketConfig { rate: 10, burst: 2 },
+            control: TokenBucketConfig { rate: 1, burst: 1 },
+            policy,
+        }
+    }
+
+    #[test]
+    fn rate_limiter_test() {
+        let packet = Command::SendPacket { sphinx_packet: vec![] };
+        let control = Command::RetrieveMessage { sequence: 0 };
+
+        let mut limiter = RateLimiter::new(test_config(RateLimitPolicy::Drop));
+        let start = limiter.packets.updated;
+        assert_eq!(limiter.check_at(&packet, start), Verdict::Accept);
+        assert_eq!(limiter.check_at(&packet, start), Verdict::Accept);
+        assert_eq!(limiter.check_at(&packet, start), Verdict::Drop);
+        // The control budget is unaffected by packets.
+        assert_eq!(limiter.check_at(&control, start), Verdict::Accept);
+        assert_eq!(limiter.check_at(&control, start), Verdict::Drop);
+        assert_eq!(limiter.check_at(&packet, start + Duration::from_millis(100)), Verdict::Accept);
+        assert_eq!(limiter.dropped_commands(), 2);
+
+        let mut limiter = RateLimiter::new(test_config(RateLimitPolicy::Delay));
+        let start = limiter.control.updated;
+        assert_eq!(limiter.check_at(&control, start), Verdict::Accept);
+        assert_eq!(limiter.check_at(&control, start), Verdict::Delay(Duration::from_secs(1)));
+        assert_eq!(limiter.check_at(&control, start), Verdict::Delay(Duration::from_secs(2)));
+
+        // A budget which never refills is not waited on.
+        let mut config = test_config(RateLimitPolicy::Delay);
+        config.control.rate = 0;
+        assert!(config.validate().is_err());
+        let mut limiter = RateLimiter::new(config);
+        let start = limiter.control.updated;
+        assert_eq!(limiter.check_at(&control, start), Verdict::Accept);
+        assert_eq!(limiter.check_at(&control, start), Verdict::Disconnect);
+
+        let mut limiter = RateLimiter::new(test_config(RateLimitPolicy::Disconnect));
+        let start = limiter.control.updated;
+        assert_eq!(limiter.check_at(&control, start), Verdict::Accept);
+        assert_eq!(limiter.check_at(&control, start), Verdict::Disconnect);
+    }
+}
@@ -0,0 +1,189 @@
+// replay.rs - handshake replay detection
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use super::constants::KEY_SIZE;
+use super::errors::ServerHandshakeError;
+
+
+struct ReplayCacheState {
+    capacity: usize,
+    window: u64,
+    seen: HashSet<[u8; KEY_SIZE]>,
+    order: VecDeque<(u64, [u8; KEY_SIZE])>,
+}
+
+impl ReplayCacheState {
+    fn expire(&mut self, now: u64) {
+        while let Some(&(seen_at, key)) = self.order.front() {
+            if now.saturating_sub(seen_at) <= self.window {
+                break
+            }
+            self.order.pop_front();
+            self.seen.remove(&key);
+        }
+    }
+}
+
+/// ReplayCache remembers the ephemeral keys of recently received
+/// initiator handshakes so that a responder can reject replayed
+/// handshake messages. Clones share the same underlying cache,
+/// therefore a listener should hand a clone to each new session.
+#[derive(Clone)]
+pub struct ReplayCache {
+    state: Arc<Mutex<ReplayCacheState>>,
+}
+
+impl ReplayCache {
+    /// Create a cache remembering at most `capacity` ephemeral keys,
+    /// each for `window` seconds. The window is also the maximum
+    /// clock skew tolerated for timestamped handshakes.
+    pub fn new(capacity: usize, window: u64) -> ReplayCache {
+        ReplayCache {
+            state: Arc::new(Mutex::new(ReplayCacheState {
+                capacity,
+                window,
+                seen: HashSet::new(),
+                order: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn window(&self) -> u64 {
+        self.state.lock().unwrap().window
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fail if the given ephemeral key was recorded within the window.
+    /// Responders look the key up as soon as the first handshake
+    /// message arrives, but only record it with `insert` once the
+    /// peer has authenticated, so that anonymous junk cannot fill
+    /// the cache.
+    pub fn check(&self, ephemeral_key: &[u8], now: u64) -> Result<(), ServerHandshakeError> {
+        let key = cache_key(ephemeral_key);
+        let mut state = self.state.lock().unwrap();
+        state.expire(now);
+        if state.seen.contains(&key) {
+            return Err(ServerHandshakeError::ReplayError)
+        }
+        Ok(())
+    }
+
+    /// Record the given ephemeral key, failing if it was already seen
+    /// within the window. Entries are never evicted before they
+    /// expire, since a peer could otherwise flush a captured key with
+    /// a flood of handshakes and then replay it. Instead, a cache full
+    /// of unexpired entries rejects every new handshake.
+    pub fn insert(&self, ephemeral_key: &[u8], now: u64) -> Result<(), ServerHandshakeError> {
+        let key = cache_key(ephemeral_key);
+        let mut state = self.state.lock().unwrap();
+        state.expire(now);
+        if state.seen.contains(&key) {
+            return Err(ServerHandshakeError::ReplayError)
+        }
+        if state.order.len() >= state.capacity {
+            return Err(ServerHandshakeError::ReplayCacheFullError)
+        }
+        state.order.push_back((now, key));
+        state.seen.insert(key);
+        Ok(())
+    }
+}
+
+fn cache_key(ephemeral_key: &[u8]) -> [u8; KEY_SIZE] {
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&ephemeral_key[..KEY_SIZE]);
+    key
+}
+
+impl PartialEq for ReplayCache {
+    fn eq(&self, other: &ReplayCache) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl fmt::Debug for ReplayCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        write!(f, "ReplayCache {{ capacity: {}, window: {}, len: {} }}",
+               state.capacity, state.window, state.order.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(cache: &ReplayCache, key: &[u8], now: u64) -> Result<(), &'static str> {
+        cache.check(key, now).map_err(|x| x.name())?;
+        cache.insert(key, now).map_err(|x| x.name())
+    }
+
+    #[test]
+    fn replay_cache_test() {
+        let cache = ReplayCache::new(2, 60);
+        let key1 = [1u8; KEY_SIZE];
+        let key2 = [2u8; KEY_SIZE];
+        let key3 = [3u8; KEY_SIZE];
+
+        assert_eq!(check(&cache, &key1, 1000), Ok(()));
+        assert_eq!(check(&cache, &key1, 1010), Err("ReplayError"));
+        assert_eq!(check(&cache.clone(), &key2, 1020), Ok(()));
+        assert_eq!(check(&cache, &key2, 1030), Err("ReplayError"));
+
+        // A full cache fails closed rather than flushing key1.
+        assert_eq!(check(&cache, &key3, 1040), Err("ReplayCacheFullError"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(check(&cache, &key1, 1050), Err("ReplayError"));
+
+        // key1 expires after the window, making room for key3.
+        assert_eq!(check(&cache, &key3, 1061), Ok(()));
+        assert_eq!(cache.len(), 2);
+
+        // everything expires after the window
+        assert_eq!(check(&cache, &key1, 1200), Ok(()));
+        assert_eq!(cache.len(), 1);
+
+        // Looking a key up does not record it.
+        assert_eq!(cache.check(&key2, 1201).map_err(|x| x.name()), Ok(()));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn flood_test() {
+        let cache = ReplayCache::new(4, 60);
+        let captured = [0u8; KEY_SIZE];
+        assert_eq!(check(&cache, &captured, 1000), Ok(()));
+
+        // Flooding the cache with fresh keys must not flush the
+        // captured key while it is still inside the window.
+        for i in 1..16u8 {
+            let _ = check(&cache, &[i; KEY_SIZE], 1001);
+        }
+        assert_eq!(cache.len(), 4);
+        assert_eq!(check(&cache, &captured, 1030), Err("ReplayError"));
+    }
+}
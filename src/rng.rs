@@ -0,0 +1,147 @@
+// rng.rs - injectable randomness for noise sessions
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate snow;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use blake2_rfc::blake2b::Blake2b;
+use snow::params::{DHChoice, HashChoice, CipherChoice};
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::types::{Random, Dh, Hash, Cipher};
+use zeroize::Zeroize;
+
+
+/// RandomSource supplies the randomness for Noise ephemeral keys.
+/// It is shared by every session built from a configuration.
+pub trait RandomSource: Send + Sync + fmt::Debug {
+    fn fill_bytes(&self, out: &mut [u8]);
+}
+
+struct DeterministicRngState {
+    seed: [u8; 32],
+    counter: u64,
+}
+
+/// DeterministicRng expands a seed with BLAKE2b in counter mode. It
+/// is only meant for reproducible test vectors, never for real links.
+pub struct DeterministicRng {
+    state: Mutex<DeterministicRngState>,
+}
+
+impl DeterministicRng {
+    pub fn new(seed: [u8; 32]) -> DeterministicRng {
+        DeterministicRng {
+            state: Mutex::new(DeterministicRngState {
+                seed,
+                counter: 0,
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for DeterministicRng {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DeterministicRng {{ <redacted> }}")
+    }
+}
+
+impl Drop for DeterministicRng {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.seed.zeroize();
+        }
+    }
+}
+
+impl RandomSource for DeterministicRng {
+    fn fill_bytes(&self, out: &mut [u8]) {
+        let mut state = self.state.lock().unwrap();
+        for chunk in out.chunks_mut(64) {
+            let mut hasher = Blake2b::with_key(64, &state.seed);
+            let mut counter = [0u8; 8];
+            for (i, b) in counter.iter_mut().enumerate() {
+                *b = (state.counter >> (8 * i)) as u8;
+            }
+            hasher.update(&counter);
+            let block = hasher.finalize();
+            chunk.copy_from_slice(&block.as_bytes()[..chunk.len()]);
+            state.counter += 1;
+        }
+    }
+}
+
+struct SharedRandom(Arc<dyn RandomSource>);
+
+impl Random for SharedRandom {
+    fn fill_bytes(&mut self, out: &mut [u8]) {
+        self.0.fill_bytes(out);
+    }
+}
+
+/// RandomSourceResolver hands a RandomSource to snow and resolves
+/// all other primitives with snow's default resolver.
+pub struct RandomSourceResolver {
+    rng: Arc<dyn RandomSource>,
+    parent: DefaultResolver,
+}
+
+impl RandomSourceResolver {
+    pub fn new(rng: Arc<dyn RandomSource>) -> RandomSourceResolver {
+        RandomSourceResolver {
+            rng,
+            parent: DefaultResolver,
+        }
+    }
+}
+
+impl CryptoResolver for RandomSourceResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random + Send>> {
+        Some(Box::new(SharedRandom(self.rng.clone())))
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh + Send>> {
+        self.parent.resolve_dh(choice)
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash + Send>> {
+        self.parent.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher + Send>> {
+        self.parent.resolve_cipher(choice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic_rng_test() {
+        let rng1 = DeterministicRng::new([1u8; 32]);
+        let rng2 = DeterministicRng::new([1u8; 32]);
+        let mut out1 = [0u8; 100];
+        let mut out2 = [0u8; 100];
+        rng1.fill_bytes(&mut out1);
+        rng2.fill_bytes(&mut out2);
+        assert_eq!(&out1[..], &out2[..]);
+        rng1.fill_bytes(&mut out2);
+        assert_ne!(&out1[..], &out2[..]);
+        assert_ne!(&out1[..36], &out1[64..]);
+    }
+}
@@ -0,0 +1,199 @@
+// serialization.rs - serde support for link types
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Serde field helpers for the `serialization` feature. Byte strings
+//! and public keys are hex strings in human readable formats such
+//! as JSON and plain bytes in binary formats.
+
+use std::cmp;
+use std::fmt;
+
+use rustc_serialize::hex::{FromHex, ToHex};
+use serde::de::{self, Deserializer, SeqAccess, Unexpected, Visitor};
+use serde::ser::Serializer;
+
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a hex string or bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+        v.from_hex().map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut v = Vec::with_capacity(cmp::min(seq.size_hint().unwrap_or(0), 4096));
+        while let Some(b) = seq.next_element()? {
+            v.push(b);
+        }
+        Ok(v)
+    }
+}
+
+pub mod bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(b: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            s.serialize_str(&b.to_hex())
+        } else {
+            s.serialize_bytes(b)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        if d.is_human_readable() {
+            d.deserialize_str(BytesVisitor)
+        } else {
+            d.deserialize_bytes(BytesVisitor)
+        }
+    }
+}
+
+fn sized_bytes<'de, D: Deserializer<'de>>(d: D, size: usize, what: &'static str) -> Result<Vec<u8>, D::Error> {
+    let b = bytes::deserialize(d)?;
+    if b.len() != size {
+        return Err(de::Error::invalid_length(b.len(), &what))
+    }
+    Ok(b)
+}
+
+/// The payload of a `MessageAck`, which must be a whole forward payload.
+pub mod ack_payload {
+    use sphinxcrypto::constants::{FORWARD_PAYLOAD_SIZE, PAYLOAD_TAG_SIZE};
+    use super::*;
+
+    pub fn serialize<S: Serializer>(b: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        bytes::serialize(b, s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        sized_bytes(d, PAYLOAD_TAG_SIZE + FORWARD_PAYLOAD_SIZE, "a MessageAck payload")
+    }
+}
+
+/// The payload of a `MessageMessage`, which must be a whole user payload.
+pub mod message_payload {
+    use sphinxcrypto::constants::USER_FORWARD_PAYLOAD_SIZE;
+    use super::*;
+
+    pub fn serialize<S: Serializer>(b: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        bytes::serialize(b, s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        sized_bytes(d, USER_FORWARD_PAYLOAD_SIZE, "a MessageMessage payload")
+    }
+}
+
+pub mod surb_id {
+    use sphinxcrypto::constants::SURB_ID_SIZE;
+    use super::*;
+
+    pub fn serialize<S: Serializer>(id: &[u8; SURB_ID_SIZE], s: S) -> Result<S::Ok, S::Error> {
+        bytes::serialize(id, s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; SURB_ID_SIZE], D::Error> {
+        let b = bytes::deserialize(d)?;
+        if b.len() != SURB_ID_SIZE {
+            return Err(de::Error::invalid_length(b.len(), &"a SURB ID"))
+        }
+        let mut id = [0u8; SURB_ID_SIZE];
+        id.copy_from_slice(&b);
+        Ok(id)
+    }
+}
+
+pub mod public_key {
+    use ecdh_wrapper::PublicKey;
+    use super::*;
+
+    pub fn serialize<S: Serializer>(key: &PublicKey, s: S) -> Result<S::Ok, S::Error> {
+        bytes::serialize(&key.to_vec(), s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<PublicKey, D::Error> {
+        let b = bytes::deserialize(d)?;
+        let mut key = PublicKey::default();
+        if key.from_bytes(&b).is_err() {
+            return Err(de::Error::invalid_length(b.len(), &"a public key"))
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    extern crate rand;
+
+    use self::rand::os::OsRng;
+    use ecdh_wrapper::PrivateKey;
+    use serde_json;
+    use sphinxcrypto::constants::{SURB_ID_SIZE, USER_FORWARD_PAYLOAD_SIZE};
+
+    use commands::{Command, VoteStatus};
+    use messages::PeerCredentials;
+
+    #[test]
+    fn json_test() {
+        let mut rng = OsRng::new().unwrap();
+        let key = PrivateKey::generate(&mut rng).unwrap().public_key();
+        let cmd = Command::Vote {
+            epoch: 7,
+            public_key: key.clone(),
+            payload: vec![0xde, 0xad],
+        };
+        let json = serde_json::to_string(&cmd).unwrap();
+        assert!(json.contains("\"payload\":\"dead\""));
+        assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), cmd);
+        assert!(serde_json::from_str::<Command>("{\"SendPacket\":{\"sphinx_packet\":\"xyz\"}}").is_err());
+
+        // Message payloads have a fixed size, which to_vec relies on.
+        let cmd = Command::MessageMessage {
+            queue_size_hint: 0,
+            sequence: 1,
+            payload: vec![0u8; USER_FORWARD_PAYLOAD_SIZE],
+        };
+        let json = serde_json::to_string(&cmd).unwrap();
+        assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), cmd);
+        assert!(serde_json::from_str::<Command>("{\"MessageMessage\":{\"queue_size_hint\":0,\"sequence\":1,\"payload\":\"00\"}}").is_err());
+        let ack = format!("{{\"MessageAck\":{{\"queue_size_hint\":0,\"sequence\":1,\"id\":\"{}\",\"payload\":\"00\"}}}}", "00".repeat(SURB_ID_SIZE));
+        assert!(serde_json::from_str::<Command>(&ack).is_err());
+
+        let creds = PeerCredentials {
+            additional_data: b"alice".to_vec(),
+            public_key: key,
+        };
+        let json = serde_json::to_string(&creds).unwrap();
+        assert_eq!(serde_json::from_str::<PeerCredentials>(&json).unwrap(), creds);
+
+        assert_eq!(serde_json::to_string(&VoteStatus::TooLate).unwrap(), "\"too_late\"");
+    }
+}
@@ -0,0 +1,146 @@
+// suite.rs - noise protocol suite selection
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use snow::params::CipherChoice;
+
+use super::constants::{KEY_SIZE, MAC_SIZE, PROLOGUE_SIZE, AUTH_MESSAGE_SIZE, VERSIONED_AUTH_MESSAGE_SIZE};
+
+
+/// The AEAD cipher of a Noise protocol suite.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum NoiseCipher {
+    ChaChaPoly,
+    AesGcm,
+}
+
+/// The hash function of a Noise protocol suite.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum NoiseHash {
+    Blake2b,
+    Sha256,
+}
+
+/// NoiseSuite selects the cipher and hash used with the XX
+/// handshake pattern over X25519. Both peers of a link must
+/// be configured with the same suite.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct NoiseSuite {
+    pub cipher: NoiseCipher,
+    pub hash: NoiseHash,
+}
+
+impl Default for NoiseSuite {
+    fn default() -> NoiseSuite {
+        NoiseSuite {
+            cipher: NoiseCipher::ChaChaPoly,
+            hash: NoiseHash::Blake2b,
+        }
+    }
+}
+
+impl NoiseSuite {
+    /// Returns the Noise protocol name, e.g.
+    /// `Noise_XX_25519_ChaChaPoly_BLAKE2b`, using the `XXpsk3`
+    /// pattern if a pre-shared key is in use.
+    pub fn noise_params(&self, psk: bool) -> String {
+        let cipher = match self.cipher {
+            NoiseCipher::ChaChaPoly => "ChaChaPoly",
+            NoiseCipher::AesGcm => "AESGCM",
+        };
+        let hash = match self.hash {
+            NoiseHash::Blake2b => "BLAKE2b",
+            NoiseHash::Sha256 => "SHA256",
+        };
+        let pattern = if psk { "XXpsk3" } else { "XX" };
+        format!("Noise_{}_25519_{}_{}", pattern, cipher, hash)
+    }
+
+    pub fn dh_size(&self) -> usize {
+        KEY_SIZE
+    }
+
+    pub fn mac_size(&self) -> usize {
+        // Both ChaChaPoly and AESGCM use 128 bit tags.
+        MAC_SIZE
+    }
+
+    /// -> (prologue), e
+    ///
+    /// With a pre-shared key the `e` token also mixes the key, so
+    /// the empty payload of the first message carries a tag.
+    pub fn handshake_message1_size(&self, psk: bool) -> usize {
+        if psk {
+            return PROLOGUE_SIZE + self.dh_size() + self.mac_size()
+        }
+        PROLOGUE_SIZE + self.dh_size()
+    }
+
+    /// <- e, ee, s, es, (auth)
+    ///
+    /// The authenticate message only carries a protocol version if
+    /// the initiator advertised versions newer than version 0.
+    pub fn handshake_message2_size(&self, versioned: bool) -> usize {
+        self.dh_size() + self.dh_size() + self.mac_size() + auth_message_size(versioned) + self.mac_size()
+    }
+
+    /// -> s, se, (auth)
+    pub fn handshake_message3_size(&self, versioned: bool) -> usize {
+        self.dh_size() + self.mac_size() + auth_message_size(versioned) + self.mac_size()
+    }
+
+    pub(crate) fn cipher_choice(&self) -> CipherChoice {
+        match self.cipher {
+            NoiseCipher::ChaChaPoly => CipherChoice::ChaChaPoly,
+            NoiseCipher::AesGcm => CipherChoice::AESGCM,
+        }
+    }
+
+    pub fn message_header_size(&self) -> usize {
+        self.mac_size() + 4
+    }
+}
+
+fn auth_message_size(versioned: bool) -> usize {
+    if versioned {
+        return VERSIONED_AUTH_MESSAGE_SIZE
+    }
+    AUTH_MESSAGE_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::constants::{NOISE_HANDSHAKE_MESSAGE1_SIZE,
+                                  NOISE_HANDSHAKE_MESSAGE2_SIZE,
+                                  NOISE_HANDSHAKE_MESSAGE3_SIZE};
+
+    #[test]
+    fn suite_test() {
+        let suite = NoiseSuite::default();
+        assert_eq!(suite.noise_params(false), "Noise_XX_25519_ChaChaPoly_BLAKE2b");
+        assert_eq!(suite.noise_params(true), "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2b");
+        assert_eq!(suite.handshake_message1_size(false), NOISE_HANDSHAKE_MESSAGE1_SIZE);
+        assert_eq!(suite.handshake_message2_size(false), NOISE_HANDSHAKE_MESSAGE2_SIZE);
+        assert_eq!(suite.handshake_message3_size(false), NOISE_HANDSHAKE_MESSAGE3_SIZE);
+        assert_eq!(suite.handshake_message2_size(true), NOISE_HANDSHAKE_MESSAGE2_SIZE + 1);
+
+        let suite = NoiseSuite {
+            cipher: NoiseCipher::AesGcm,
+            hash: NoiseHash::Sha256,
+        };
+        assert_eq!(suite.noise_params(false), "Noise_XX_25519_AESGCM_SHA256");
+    }
+}
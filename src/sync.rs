@@ -17,15 +17,21 @@
 extern crate snow;
 extern crate ecdh_wrapper;
 
-use std::net::{TcpStream, Shutdown};
+use std::io;
 use std::io::prelude::*;
 use std::sync::{Arc, Mutex};
+use std::thread;
 
+use zeroize::Zeroize;
+
+use super::allowlist::Registration;
 use super::commands::{Command};
-use super::errors::{HandshakeError, ReceiveMessageError, SendMessageError};
-use super::messages::{MessageBuilder, SessionConfig, PeerCredentials};
-use super::constants::{NOISE_HANDSHAKE_MESSAGE1_SIZE, NOISE_HANDSHAKE_MESSAGE2_SIZE,
-                       NOISE_HANDSHAKE_MESSAGE3_SIZE};
+use super::errors::{ExportError, HandshakeError, ReceiveMessageError, SendMessageError};
+use super::messages::{MessageBuilder, MessageSender, MessageReceiver, SessionConfig, PeerCredentials};
+use super::logging::fingerprint;
+use super::metrics::Metrics;
+use super::rate_limit::{RateLimits, RateLimiter, Verdict};
+use super::transport::Transport;
 
 
 const MAC_LEN: usize = 16;
@@ -33,150 +39,265 @@ const MAX_MSG_LEN: usize = 1_048_576;
 
 /// A mixnet link layer protocol session.
 pub struct Session {
-    reader_tcp_stream: Option<TcpStream>,
-    writer_tcp_stream: Option<TcpStream>,
+    reader_transport: Option<Box<dyn Transport>>,
+    writer_transport: Option<Box<dyn Transport>>,
     is_initiator: bool,
     handshake_builder: Option<MessageBuilder>,
     transport_builder: Option<Arc<Mutex<MessageBuilder>>>,
+    /// The peer's credentials, copied out of the shared transport
+    /// builder so they can be borrowed.
+    peer_credentials: Option<PeerCredentials>,
+    rate_limits: RateLimits,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    registration: Option<Arc<Registration>>,
 }
 
 impl Clone for Session {
     fn clone(&self) -> Session {
         Session {
-            reader_tcp_stream: Some(self.reader_tcp_stream.as_ref().unwrap().try_clone().unwrap()),
-            writer_tcp_stream: Some(self.writer_tcp_stream.as_ref().unwrap().try_clone().unwrap()),
+            reader_transport: Some(self.reader_transport.as_ref().unwrap().try_clone_transport().unwrap()),
+            writer_transport: Some(self.writer_transport.as_ref().unwrap().try_clone_transport().unwrap()),
             is_initiator: self.is_initiator,
             handshake_builder: None,
             transport_builder: self.transport_builder.clone(),
+            peer_credentials: self.peer_credentials.clone(),
+            rate_limits: self.rate_limits,
+            rate_limiter: self.rate_limiter.clone(),
+            metrics: self.metrics.clone(),
+            registration: self.registration.clone(),
         }
     }
 }
 
 impl Session {
     pub fn new(cfg: SessionConfig, is_initiator: bool) -> Result<Session, HandshakeError> {
+        cfg.rate_limits.validate()?;
+        let rate_limits = cfg.rate_limits;
+        let metrics = cfg.metrics.clone();
         Ok(Session{
-            writer_tcp_stream: None,
-            reader_tcp_stream: None,
+            writer_transport: None,
+            reader_transport: None,
             is_initiator,
             handshake_builder: Some(MessageBuilder::new(cfg, is_initiator)?),
             transport_builder: None,
+            peer_credentials: None,
+            rate_limits,
+            rate_limiter: None,
+            metrics,
+            registration: None,
         })
     }
 
     fn handshake(&mut self) -> Result<(), HandshakeError>{
-        let tcp_reader = self.reader_tcp_stream.as_mut().unwrap();
-        let tcp_writer = self.writer_tcp_stream.as_mut().unwrap();
+        let reader = self.reader_transport.as_mut().unwrap();
+        let writer = self.writer_transport.as_mut().unwrap();
         let factory = self.handshake_builder.as_mut().unwrap();
         if self.is_initiator {
             // c -> s
             let client_handshake1 = factory.client_handshake1()?;
-            tcp_writer.write_all(&client_handshake1)?;
+            writer.write_all(&client_handshake1)?;
+            count_bytes_sent(&self.metrics, client_handshake1.len());
             factory.sent_client_handshake1();
 
             // s -> c
-            let mut server_handshake1 = [0; NOISE_HANDSHAKE_MESSAGE2_SIZE];
-            tcp_reader.read_exact(&mut server_handshake1)?;
-            factory.received_server_handshake1(server_handshake1)?;
+            let mut server_handshake1 = vec![0u8; factory.handshake_message2_size()];
+            reader.read_exact(&mut server_handshake1)?;
+            count_bytes_received(&self.metrics, server_handshake1.len());
+            factory.received_server_handshake1(&server_handshake1)?;
 
             // c -> s
             let client_handshake2 = factory.client_handshake2()?;
-            tcp_writer.write_all(&client_handshake2)?;
+            writer.write_all(&client_handshake2)?;
+            count_bytes_sent(&self.metrics, client_handshake2.len());
             factory.sent_client_handshake2();
         } else {
             // c -> s
-            let mut client_handshake1 = [0u8; NOISE_HANDSHAKE_MESSAGE1_SIZE];
-            tcp_reader.read_exact(&mut client_handshake1)?;
-            let server_handshake1 = factory.received_client_handshake1(client_handshake1).unwrap();
+            let mut client_handshake1 = vec![0u8; factory.handshake_message1_size()];
+            reader.read_exact(&mut client_handshake1)?;
+            count_bytes_received(&self.metrics, client_handshake1.len());
+            let server_handshake1 = factory.received_client_handshake1(&client_handshake1)?;
 
             // s -> c
-            tcp_writer.write_all(&server_handshake1)?;
+            writer.write_all(&server_handshake1)?;
+            count_bytes_sent(&self.metrics, server_handshake1.len());
             factory.sent_server_handshake1();
 
             // c -> s
-            let mut client_handshake2 = [0u8; NOISE_HANDSHAKE_MESSAGE3_SIZE];
-            tcp_reader.read_exact(&mut client_handshake2)?;
-            factory.received_client_handshake2(client_handshake2).unwrap();
+            let mut client_handshake2 = vec![0u8; factory.handshake_message3_size()];
+            reader.read_exact(&mut client_handshake2)?;
+            count_bytes_received(&self.metrics, client_handshake2.len());
+            factory.received_client_handshake2(&client_handshake2)?;
         }
         Ok(())
     }
 
     pub fn finalize_handshake(&mut self) -> Result<(), HandshakeError>{
         if self.is_initiator {
-            let cmd = self.recv_command().unwrap();
+            let cmd = self.recv_command()?;
             match cmd {
                 Command::NoOp{} => return Ok(()),
                 _ => return Err(HandshakeError::InvalidHandshakeFinalize),
             }
         }
         let cmd = Command::NoOp{};
-        self.send_command(&cmd).unwrap();
+        self.send_command(&cmd)?;
         Ok(())
     }
         
-    pub fn initialize(&mut self, tcp_stream: TcpStream) -> Result<(), HandshakeError>{
-        let reader_tcp_stream = tcp_stream.try_clone()?;
-        self.reader_tcp_stream = Some(reader_tcp_stream);
-        self.writer_tcp_stream = Some(tcp_stream);
-        self.handshake()?;
-        Ok(())
+    /// Run the handshake over the given transport, e.g. a TcpStream.
+    pub fn initialize<T: Transport + 'static>(&mut self, transport: T) -> Result<(), HandshakeError>{
+        self.reader_transport = Some(transport.try_clone_transport()?);
+        self.writer_transport = Some(Box::new(transport));
+        log_debug!("starting handshake as {}", if self.is_initiator { "initiator" } else { "responder" });
+        let result = self.handshake();
+        let peer = match self.handshake_builder.as_ref().unwrap().peer_public_key() {
+            Some(key) => fingerprint(key),
+            None => "unknown peer".to_string(),
+        };
+        match result {
+            Ok(_) => log_info!("handshake with {} completed", peer),
+            Err(ref e) => log_warn!("handshake with {} failed: {}", peer, e),
+        }
+        if let Some(ref metrics) = self.metrics {
+            match result {
+                Ok(_) => metrics.handshake_completed(self.is_initiator),
+                Err(ref e) => metrics.handshake_failed(self.is_initiator, e),
+            }
+        }
+        result
     }
 
     pub fn into_transport_mode(mut self) -> Result<Self, HandshakeError> {
+        let builder = self.handshake_builder.take().unwrap().into_transport_mode()?;
+        let rate_limiter = self.rate_limits.for_peer(builder.authenticator.is_peer_client())
+            .map(|config| Arc::new(Mutex::new(RateLimiter::new(config))));
+        // Let the allowlist which admitted the peer close this session
+        // if the peer is later removed from it.
+        let registration = match builder.authenticator.allowlist() {
+            Some(allowlist) => {
+                let writer = self.writer_transport.as_ref().unwrap();
+                allowlist.register(&builder.peer_credentials().public_key, &**writer)?.map(Arc::new)
+            },
+            None => None,
+        };
         Ok(Self {
-            reader_tcp_stream: self.reader_tcp_stream,
-            writer_tcp_stream: self.writer_tcp_stream,
+            reader_transport: self.reader_transport,
+            writer_transport: self.writer_transport,
             is_initiator: self.is_initiator,
             handshake_builder: None,
-            transport_builder: Some(Arc::new(Mutex::new(self.handshake_builder.take().unwrap().into_transport_mode()?))),
+            peer_credentials: Some(builder.peer_credentials().clone()),
+            transport_builder: Some(Arc::new(Mutex::new(builder))),
+            rate_limits: self.rate_limits,
+            rate_limiter,
+            metrics: self.metrics,
+            registration,
         })
     }
 
     pub fn send_command(&mut self, cmd: &Command) -> Result<(), SendMessageError> {
-        let ct = cmd.to_vec();
-        let ct_len = MAC_LEN + ct.len();
-        if ct_len > MAX_MSG_LEN {
-            return Err(SendMessageError::InvalidMessageSize);
-        }
-
-        let mut to_send = vec![];
-        to_send.extend(self.transport_builder.as_mut().unwrap().lock().unwrap().encrypt_message(&ct)?);
-
-        // XXX https://github.com/mcginty/snow/issues/35
-
-        self.writer_tcp_stream.as_mut().unwrap().write_all(&to_send)?;
-        Ok(())
+        let builder = self.transport_builder.as_ref().unwrap();
+        send_command(self.writer_transport.as_mut().unwrap(), &self.metrics, cmd, |ct| builder.lock().unwrap().encrypt_message(ct))
     }
 
     pub fn recv_command(&mut self) -> Result<Command, ReceiveMessageError> {
-        // Read, decrypt and parse the ciphertext header.
-        let mut header_ciphertext = vec![0u8; MAC_LEN + 4];
-        self.reader_tcp_stream.as_mut().unwrap().read_exact(&mut header_ciphertext)?;
-        let ct_len = self.transport_builder.as_mut().unwrap().lock().unwrap().decrypt_message_header(&header_ciphertext.to_vec())?;
-
-        // Read and decrypt the ciphertext.
-        let mut ct = vec![0u8; ct_len as usize];
-        self.reader_tcp_stream.as_mut().unwrap().read_exact(&mut ct)?;
-        let body = self.transport_builder.as_mut().unwrap().lock().unwrap().decrypt_message(&ct)?;
+        let reader = self.reader_transport.as_mut().unwrap();
+        loop {
+            let cmd = recv_command(reader, &self.metrics, self.transport_builder.as_ref().unwrap())?;
+            if let Some(cmd) = rate_limit(reader, self.rate_limiter.as_ref(), cmd)? {
+                return Ok(cmd)
+            }
+        }
+    }
 
-        // XXX https://github.com/mcginty/snow/issues/35
+    /// Returns the number of commands from the peer that exceeded
+    /// their rate limit.
+    pub fn rate_limited_commands(&self) -> u64 {
+        match self.rate_limiter {
+            Some(ref limiter) => limiter.lock().unwrap().dropped_commands(),
+            None => 0,
+        }
+    }
 
-        Ok(Command::from_bytes(&body)?)
+    /// Split a session in transport mode into a sender and a receiver
+    /// which can be used from different threads without sharing a
+    /// lock. Fails if the session has been cloned.
+    pub fn split(self) -> Result<(SessionSender, SessionReceiver), HandshakeError> {
+        let builder = match self.transport_builder {
+            Some(builder) => builder,
+            None => return Err(HandshakeError::InvalidStateError),
+        };
+        let builder = match Arc::try_unwrap(builder) {
+            Ok(x) => x.into_inner().unwrap(),
+            Err(_) => return Err(HandshakeError::InvalidStateError),
+        };
+        let (sender, receiver) = builder.split()?;
+        Ok((SessionSender {
+            writer_transport: self.writer_transport.unwrap(),
+            sender,
+            metrics: self.metrics.clone(),
+            _registration: self.registration.clone(),
+        }, SessionReceiver {
+            reader_transport: self.reader_transport.unwrap(),
+            receiver,
+            rate_limiter: self.rate_limiter,
+            metrics: self.metrics,
+            _registration: self.registration,
+        }))
     }
 
     pub fn close(&mut self) {
+        log_debug!("closing session");
         // XXX https://github.com/mcginty/snow/issues/35
-        let _ = self.reader_tcp_stream.as_mut().unwrap().shutdown(Shutdown::Both);
-        let _ = self.writer_tcp_stream.as_mut().unwrap().shutdown(Shutdown::Both);
+        let _ = self.reader_transport.as_mut().unwrap().shutdown_transport();
+        let _ = self.writer_transport.as_mut().unwrap().shutdown_transport();
     }
 
     pub fn peer_credentials(&self) -> &PeerCredentials {
-        self.handshake_builder.as_ref().unwrap().peer_credentials()
+        if let Some(ref builder) = self.handshake_builder {
+            return builder.peer_credentials()
+        }
+        self.peer_credentials.as_ref().unwrap()
+    }
+
+    pub fn protocol_version(&self) -> Option<u8> {
+        if let Some(ref builder) = self.handshake_builder {
+            return builder.protocol_version()
+        }
+        self.transport_builder.as_ref().unwrap().lock().unwrap().protocol_version()
+    }
+
+    /// Returns the Noise handshake hash of this link, once the
+    /// handshake has completed.
+    pub fn handshake_hash(&self) -> Option<Vec<u8>> {
+        match self.transport_builder {
+            Some(ref builder) => builder.lock().unwrap().handshake_hash().map(|x| x.to_vec()),
+            None => None,
+        }
+    }
+
+    /// Derive keying material bound to this link, see
+    /// `MessageBuilder::export_keying_material`.
+    pub fn export_keying_material(&self, label: &[u8], context: &[u8], length: usize) -> Result<Vec<u8>, ExportError> {
+        match self.transport_builder {
+            Some(ref builder) => builder.lock().unwrap().export_keying_material(label, context, length),
+            None => Err(ExportError::HandshakeIncompleteError),
+        }
     }
 
     pub fn clock_skew(&self) -> u64 {
         self.transport_builder.as_ref().unwrap().lock().unwrap().clock_skew()
     }
 
+    /// Returns the index of the pinned key which the peer presented,
+    /// see `ClientAuthenticatorState`.
+    pub fn matched_key(&self) -> Option<usize> {
+        if let Some(ref builder) = self.handshake_builder {
+            return builder.authenticator.matched_key()
+        }
+        self.transport_builder.as_ref().unwrap().lock().unwrap().authenticator.matched_key()
+    }
+
     pub fn from_client(&self) -> bool {
         assert!(!self.is_initiator);
         assert!(self.transport_builder.is_some());
@@ -184,206 +305,600 @@ impl Session {
     }
 }
 
+/// The sending half of a split session.
+pub struct SessionSender {
+    writer_transport: Box<dyn Transport>,
+    sender: MessageSender,
+    metrics: Option<Arc<dyn Metrics>>,
+    _registration: Option<Arc<Registration>>,
+}
+
+impl SessionSender {
+    pub fn send_command(&mut self, cmd: &Command) -> Result<(), SendMessageError> {
+        let sender = &mut self.sender;
+        send_command(&mut self.writer_transport, &self.metrics, cmd, |ct| sender.encrypt_message(ct))
+    }
+
+    pub fn close(&mut self) {
+        let _ = self.writer_transport.shutdown_transport();
+    }
+
+    /// Returns another handle to the link, which can shut it down
+    /// while this sender is blocked writing to it.
+    pub(crate) fn shutdown_handle(&self) -> io::Result<Box<dyn Transport>> {
+        self.writer_transport.try_clone_transport()
+    }
+}
+
+/// The receiving half of a split session.
+pub struct SessionReceiver {
+    reader_transport: Box<dyn Transport>,
+    receiver: MessageReceiver,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    _registration: Option<Arc<Registration>>,
+}
+
+impl SessionReceiver {
+    pub fn recv_command(&mut self) -> Result<Command, ReceiveMessageError> {
+        loop {
+            let cmd = recv_command(&mut self.reader_transport, &self.metrics, &mut self.receiver)?;
+            if let Some(cmd) = rate_limit(&mut self.reader_transport, self.rate_limiter.as_ref(), cmd)? {
+                return Ok(cmd)
+            }
+        }
+    }
+
+    pub fn close(&mut self) {
+        let _ = self.reader_transport.shutdown_transport();
+    }
+}
+
+fn count_bytes_sent(metrics: &Option<Arc<dyn Metrics>>, count: usize) {
+    if let Some(ref metrics) = *metrics {
+        metrics.bytes_sent(count);
+    }
+}
+
+fn count_bytes_received(metrics: &Option<Arc<dyn Metrics>>, count: usize) {
+    if let Some(ref metrics) = *metrics {
+        metrics.bytes_received(count);
+    }
+}
+
+fn send_command<F>(writer: &mut Box<dyn Transport>, metrics: &Option<Arc<dyn Metrics>>, cmd: &Command, encrypt: F) -> Result<(), SendMessageError>
+    where F: FnOnce(&[u8]) -> Result<Vec<u8>, SendMessageError> {
+    let mut ct = cmd.to_vec();
+    let ct_len = MAC_LEN + ct.len();
+    if ct_len > MAX_MSG_LEN {
+        ct.zeroize();
+        return Err(SendMessageError::InvalidMessageSize);
+    }
+
+    let mut to_send = vec![];
+    let result = encrypt(&ct);
+    ct.zeroize();
+    to_send.extend(result?);
+
+    // XXX https://github.com/mcginty/snow/issues/35
+
+    writer.write_all(&to_send)?;
+    if let Some(ref metrics) = *metrics {
+        metrics.bytes_sent(to_send.len());
+        metrics.command_sent(cmd.name());
+    }
+    Ok(())
+}
+
+/// Decrypt is the receiving side of a transport cipher.
+trait Decrypt {
+    fn decrypt_message_header(&mut self, message: &[u8]) -> Result<u32, ReceiveMessageError>;
+    fn decrypt_message(&mut self, message: &[u8]) -> Result<Vec<u8>, ReceiveMessageError>;
+}
+
+// The lock is only held while decrypting, never while reading.
+impl<'a> Decrypt for &'a Arc<Mutex<MessageBuilder>> {
+    fn decrypt_message_header(&mut self, message: &[u8]) -> Result<u32, ReceiveMessageError> {
+        self.lock().unwrap().decrypt_message_header(message)
+    }
+
+    fn decrypt_message(&mut self, message: &[u8]) -> Result<Vec<u8>, ReceiveMessageError> {
+        self.lock().unwrap().decrypt_message(message)
+    }
+}
+
+impl<'a> Decrypt for &'a mut MessageReceiver {
+    fn decrypt_message_header(&mut self, message: &[u8]) -> Result<u32, ReceiveMessageError> {
+        (**self).decrypt_message_header(message)
+    }
+
+    fn decrypt_message(&mut self, message: &[u8]) -> Result<Vec<u8>, ReceiveMessageError> {
+        (**self).decrypt_message(message)
+    }
+}
+
+fn recv_command<D: Decrypt>(reader: &mut Box<dyn Transport>, metrics: &Option<Arc<dyn Metrics>>, decrypter: D) -> Result<Command, ReceiveMessageError> {
+    let result = read_command(reader, metrics, decrypter);
+    match result {
+        Ok(ref cmd) => {
+            if let Some(ref metrics) = *metrics {
+                metrics.command_received(cmd.name());
+            }
+        },
+        Err(ReceiveMessageError::DecryptFail) => {
+            log_warn!("failed to decrypt message");
+            if let Some(ref metrics) = *metrics {
+                metrics.decrypt_failed();
+            }
+        },
+        Err(ReceiveMessageError::CommandError(ref e)) => log_debug!("failed to decode command: {}", e),
+        Err(_) => {},
+    }
+    result
+}
+
+fn read_command<D: Decrypt>(reader: &mut Box<dyn Transport>, metrics: &Option<Arc<dyn Metrics>>, mut decrypter: D) -> Result<Command, ReceiveMessageError> {
+    // Read, decrypt and parse the ciphertext header.
+    let mut header_ciphertext = vec![0u8; MAC_LEN + 4];
+    reader.read_exact(&mut header_ciphertext)?;
+    count_bytes_received(metrics, header_ciphertext.len());
+    let ct_len = decrypter.decrypt_message_header(&header_ciphertext)?;
+
+    // Read and decrypt the ciphertext.
+    let mut ct = vec![0u8; ct_len as usize];
+    reader.read_exact(&mut ct)?;
+    count_bytes_received(metrics, ct.len());
+    let mut body = decrypter.decrypt_message(&ct)?;
+
+    // XXX https://github.com/mcginty/snow/issues/35
+
+    let cmd = Command::from_bytes(&body);
+    body.zeroize();
+    Ok(cmd?)
+}
+
+/// Apply the peer's rate limit to a received command, returning
+/// None if it is to be discarded.
+fn rate_limit(reader: &mut Box<dyn Transport>, limiter: Option<&Arc<Mutex<RateLimiter>>>, cmd: Command) -> Result<Option<Command>, ReceiveMessageError> {
+    let verdict = match limiter {
+        Some(limiter) => limiter.lock().unwrap().check(&cmd),
+        None => Verdict::Accept,
+    };
+    match verdict {
+        Verdict::Accept => Ok(Some(cmd)),
+        Verdict::Delay(wait) => {
+            thread::sleep(wait);
+            Ok(Some(cmd))
+        },
+        Verdict::Drop => Ok(None),
+        Verdict::Disconnect => {
+            log_warn!("peer exceeded its rate limit, disconnecting");
+            let _ = reader.shutdown_transport();
+            Err(ReceiveMessageError::RateLimitExceeded)
+        },
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     extern crate rand;
     extern crate ecdh_wrapper;
 
     use std::{thread, time};
     use std::time::Duration;
-    use std::net::TcpListener;
-    use std::net::TcpStream;
+    use std::sync::Arc;
     use self::rand::os::OsRng;
     use ecdh_wrapper::PrivateKey;
-    use super::{Session, SessionConfig};
+    use zeroize::Zeroizing;
+    use super::{Session, SessionConfig, SessionSender, SessionReceiver};
     use super::super::messages::{PeerAuthenticator, ProviderAuthenticatorState, ClientAuthenticatorState};
+    use super::super::constants::NOISE_HANDSHAKE_MESSAGE2_SIZE;
+    use super::super::errors::{ClientHandshakeError, ServerHandshakeError, HandshakeError};
+    use super::super::metrics::InMemoryMetrics;
+    use super::super::rate_limit::{RateLimitConfig, RateLimitPolicy, TokenBucketConfig};
     use super::super::commands::{Command};
+    use super::super::transport::{duplex, simulated_duplex, NetworkConditions};
+    use super::super::sphinxcrypto::constants::USER_FORWARD_PAYLOAD_SIZE;
 
 
-    #[test]
-    fn handshake_test() {
-        let mut threads = vec![];
-        let server_addr = "127.0.0.1:8000";
+    pub(crate) fn test_configs() -> (SessionConfig, SessionConfig) {
         let mut rng = OsRng::new().expect("failure to create an OS RNG");
         let server_keypair = PrivateKey::generate(&mut rng).unwrap();
         let client_keypair = PrivateKey::generate(&mut rng).unwrap();
 
-        let mut provider_auth = ProviderAuthenticatorState::default();
-        provider_auth.client_map.insert(client_keypair.public_key(), true);
-        let provider_authenticator = PeerAuthenticator::Provider(provider_auth);
+        let provider_auth = ProviderAuthenticatorState::default();
+        provider_auth.client_map.insert(client_keypair.public_key());
+        let server_config = SessionConfig::test_config(PeerAuthenticator::Provider(provider_auth), server_keypair.clone(), None);
 
         let mut client_auth = ClientAuthenticatorState::default();
-        client_auth.peer_public_key = server_keypair.public_key();
-        let client_authenticator = PeerAuthenticator::Client(client_auth);
-
-        let server_keypair_clone = server_keypair.clone();
-
-        // server listener
-        threads.push(thread::spawn(move|| {
-            let listener = TcpListener::bind(server_addr.clone()).expect("could not start server");
-
-            // server
-            let server_config = SessionConfig {
-                authenticator: provider_authenticator,
-                authentication_key: server_keypair,
-                peer_public_key: None,
-                additional_data: vec![],
-            };
+        client_auth.peer_public_keys = vec![server_keypair.public_key()];
+        let mut client_config = SessionConfig::test_config(PeerAuthenticator::Client(client_auth), client_keypair, Some(server_keypair.public_key()));
+        client_config.additional_data = b"alice".to_vec();
+        (client_config, server_config)
+    }
+
+    #[test]
+    fn handshake_test() {
+        let (client_config, server_config) = test_configs();
+        let conditions = NetworkConditions {
+            latency: Duration::from_millis(10),
+            ..NetworkConditions::default()
+        };
+        let (client_stream, server_stream) = simulated_duplex(conditions.clone(), conditions);
+
+        let server = thread::spawn(move|| {
             let mut session = Session::new(server_config, false).unwrap();
+            session.initialize(server_stream).unwrap();
+            session = session.into_transport_mode().unwrap();
+            session.finalize_handshake().unwrap();
+            assert_eq!(session.peer_credentials().username().unwrap(), "alice");
+            assert_eq!(session.protocol_version(), Some(0));
+            assert!(session.handshake_hash().is_some());
+            session.export_keying_material(b"test", b"", 32).unwrap()
+        });
+
+        let mut session = Session::new(client_config, true).unwrap();
+        session.initialize(client_stream).unwrap();
+        session = session.into_transport_mode().unwrap();
+        session.finalize_handshake().unwrap();
+        let exported = session.export_keying_material(b"test", b"", 32).unwrap();
+        assert_eq!(server.join().unwrap(), exported);
+        session.close();
+    }
 
-            for connection in listener.incoming() {
-                match connection {
-                    Ok(mut stream) => {
-                        session.initialize(stream).unwrap();
+    #[test]
+    fn reader_writer_thread_test() {
+        let (client_config, server_config) = test_configs();
+        let (client_stream, server_stream) = duplex();
 
-                        session = session.into_transport_mode().unwrap();
-                        session.finalize_handshake().unwrap();
-                        session.close();
+        let server = thread::spawn(move|| {
+            let mut session = Session::new(server_config, false).unwrap();
+            session.initialize(server_stream).unwrap();
+            session = session.into_transport_mode().unwrap();
+            session.finalize_handshake().unwrap();
+
+            let mut reader_session = session.clone();
+            let reader = thread::spawn(move|| {
+                let mut received = 0;
+                while reader_session.recv_command().is_ok() {
+                    received += 1;
+                }
+                reader_session.close();
+                received
+            });
+            let writer = thread::spawn(move|| {
+                loop {
+                    if session.send_command(&Command::NoOp{}).is_err() {
                         return
                     }
-                    Err(e) => { println!("connection failed {}", e); }
+                    thread::sleep(time::Duration::from_millis(10));
                 }
-            }
-        }));
-
-        // client dialer
-        threads.push(thread::spawn(move|| {
-            thread::sleep(Duration::from_secs(1));
-            // client
-            let client_config = SessionConfig {
-                authenticator: client_authenticator,
-                authentication_key: client_keypair,
-                peer_public_key: Some(server_keypair_clone.public_key()),
-                additional_data: vec![],
-            };
-            let mut session = Session::new(client_config, true).unwrap();
+            });
+            let received = reader.join().unwrap();
+            writer.join().unwrap();
+            received
+        });
+
+        let mut session = Session::new(client_config, true).unwrap();
+        session.initialize(client_stream).unwrap();
+        session = session.into_transport_mode().unwrap();
+        session.finalize_handshake().unwrap();
+        for _ in 0..3 {
+            session.recv_command().unwrap();
+            session.send_command(&Command::NoOp{}).unwrap();
+        }
+        session.recv_command().unwrap();
+        session.close();
+        assert_eq!(server.join().unwrap(), 3);
+    }
 
-            let stream = TcpStream::connect(server_addr.clone()).expect("connection failed");
-            session.initialize(stream).unwrap();
-            println!("client handshake completed!");
+    #[test]
+    fn truncated_handshake_test() {
+        let (client_config, server_config) = test_configs();
+        let conditions = NetworkConditions {
+            truncate_after: Some(10),
+            ..NetworkConditions::default()
+        };
+        let (client_stream, server_stream) = simulated_duplex(conditions, NetworkConditions::default());
+
+        let server = thread::spawn(move|| {
+            let mut session = Session::new(server_config, false).unwrap();
+            session.initialize(server_stream).is_err()
+        });
+        let mut session = Session::new(client_config, true).unwrap();
+        assert!(session.initialize(client_stream).is_err());
+        assert!(server.join().unwrap());
+    }
 
+    #[test]
+    fn corrupted_transport_test() {
+        let (client_config, server_config) = test_configs();
+        // Corrupt the header of the first transport message.
+        let conditions = NetworkConditions {
+            bit_flips: vec![NOISE_HANDSHAKE_MESSAGE2_SIZE + 3],
+            ..NetworkConditions::default()
+        };
+        let (client_stream, server_stream) = simulated_duplex(NetworkConditions::default(), conditions);
+
+        let server = thread::spawn(move|| {
+            let mut session = Session::new(server_config, false).unwrap();
+            session.initialize(server_stream).unwrap();
             session = session.into_transport_mode().unwrap();
             session.finalize_handshake().unwrap();
-            session.close();
-        }));
-
-        // wait for spawned threads to exit
-        for t in threads {
-            let _ = t.join();
+        });
+        let mut session = Session::new(client_config, true).unwrap();
+        session.initialize(client_stream).unwrap();
+        session = session.into_transport_mode().unwrap();
+        match session.finalize_handshake() {
+            Err(HandshakeError::ReceiveMessageError(_)) => {},
+            _ => panic!("expected a decryption failure"),
         }
+        server.join().unwrap();
     }
 
     #[test]
-    fn reader_writer_thread_test() {
-        let mut threads = vec![];
-        let server_addr = "127.0.0.1:8001";
-        let mut rng = OsRng::new().expect("failure to create an OS RNG");
-        let server_keypair = PrivateKey::generate(&mut rng).unwrap();
-        let client_keypair = PrivateKey::generate(&mut rng).unwrap();
+    fn split_test() {
+        // Each half is moved to its own thread, so both must be Send.
+        fn assert_send<T: Send>() {}
+        assert_send::<SessionSender>();
+        assert_send::<SessionReceiver>();
 
-        let mut provider_auth = ProviderAuthenticatorState::default();
-        provider_auth.client_map.insert(client_keypair.public_key(), true);
-        let provider_authenticator = PeerAuthenticator::Provider(provider_auth);
+        let (client_config, server_config) = test_configs();
+        assert!(Session::new(client_config.clone(), true).unwrap().split().is_err());
+        let (client_stream, server_stream) = duplex();
 
-        let mut client_auth = ClientAuthenticatorState::default();
-        client_auth.peer_public_key = server_keypair.public_key();
-        let client_authenticator = PeerAuthenticator::Client(client_auth);
-
-        let server_keypair_clone = server_keypair.clone();
-
-        // server listener
-        threads.push(thread::spawn(move|| {
-            let listener = TcpListener::bind(server_addr.clone()).expect("could not start server");
-
-            // server
-            let server_config = SessionConfig {
-                authenticator: provider_authenticator,
-                authentication_key: server_keypair,
-                peer_public_key: None,
-                additional_data: vec![],
-            };
+        let server = thread::spawn(move|| {
             let mut session = Session::new(server_config, false).unwrap();
-
-            for connection in listener.incoming() {
-                match connection {
-                    Ok(mut stream) => {
-                        session.initialize(stream).unwrap();
-                        session = session.into_transport_mode().unwrap();
-                        session.finalize_handshake().unwrap();
-
-                        let mut reader_session = session.clone();
-                        let mut session_threads = vec![];
-                        session_threads.push(thread::spawn(move|| {
-                            loop {
-                                if let Ok(cmd) = reader_session.recv_command() {
-                                    println!("server received command {:?}", cmd);
-                                } else {
-                                    reader_session.close();
-                                    return
-                                }
-                            }
-                        }));
-                        session_threads.push(thread::spawn(move|| {
-                            loop {
-                                let cmd = Command::NoOp{};
-                                println!("server send NoOp");
-                                match session.send_command(&cmd) {
-                                    Ok(_) => {},
-                                    Err(_) => return,
-                                }
-                                thread::sleep(time::Duration::from_secs(2));
-                            }
-                        }));
-                        for t in session_threads {
-                            let _ = t.join();
-                        }
-                        // XXX
-                        //session.close();
-                        return
-                    }
-                    Err(e) => { println!("connection failed {}", e); }
+            session.initialize(server_stream).unwrap();
+            session = session.into_transport_mode().unwrap();
+            session.finalize_handshake().unwrap();
+            let (mut sender, mut receiver) = session.split().unwrap();
+            let writer = thread::spawn(move|| {
+                for i in 0..10 {
+                    sender.send_command(&Command::MessageMessage {
+                        queue_size_hint: 0,
+                        sequence: i,
+                        payload: vec![0u8; USER_FORWARD_PAYLOAD_SIZE],
+                    }).unwrap();
                 }
+            });
+            for _ in 0..10 {
+                assert_eq!(receiver.recv_command().unwrap(), Command::NoOp{});
             }
-        }));
-
-        // client dialer
-        threads.push(thread::spawn(move|| {
-            thread::sleep(Duration::from_secs(1));
-            // client
-            let client_config = SessionConfig {
-                authenticator: client_authenticator,
-                authentication_key: client_keypair,
-                peer_public_key: Some(server_keypair_clone.public_key()),
-                additional_data: vec![],
-            };
-            let mut session = Session::new(client_config, true).unwrap();
+            writer.join().unwrap();
+        });
+
+        let mut session = Session::new(client_config, true).unwrap();
+        session.initialize(client_stream).unwrap();
+        session = session.into_transport_mode().unwrap();
+        session.finalize_handshake().unwrap();
+        let (mut sender, mut receiver) = session.split().unwrap();
+        let writer = thread::spawn(move|| {
+            for _ in 0..10 {
+                sender.send_command(&Command::NoOp{}).unwrap();
+            }
+        });
+        for i in 0..10 {
+            match receiver.recv_command().unwrap() {
+                Command::MessageMessage { sequence, .. } => assert_eq!(sequence, i),
+                _ => panic!("unexpected command"),
+            }
+        }
+        writer.join().unwrap();
+        server.join().unwrap();
+    }
 
-            let stream = TcpStream::connect(server_addr.clone()).expect("connection failed");
-            session.initialize(stream).unwrap();
+    #[test]
+    fn revocation_test() {
+        let (client_config, server_config) = test_configs();
+        let allowlist = match server_config.authenticator {
+            PeerAuthenticator::Provider(ref state) => state.client_map.clone(),
+            _ => unreachable!(),
+        };
+        allowlist.set_terminate_revoked(true);
+        let client_key = client_config.authentication_key.public_key();
+        let (client_stream, server_stream) = duplex();
+
+        let server = thread::spawn(move|| {
+            let mut session = Session::new(server_config, false).unwrap();
+            session.initialize(server_stream).unwrap();
             session = session.into_transport_mode().unwrap();
             session.finalize_handshake().unwrap();
-            println!("client handshake completed!");
-
-            let mut acc = 0;
-            loop {
-                match session.recv_command() {
-                    Ok(_cmd) => {
-                        if acc == 3 {
-                            session.close();
-                            return
-                        }
-                        println!("client received command, sending NoOp response");
-                        session.send_command(&Command::NoOp{}).unwrap();
-                        acc += 1;
-                    },
-                    Err(e) => println!("client receive command err: {}", e),
-                }
+            session
+        });
+
+        let mut session = Session::new(client_config, true).unwrap();
+        session.initialize(client_stream).unwrap();
+        session = session.into_transport_mode().unwrap();
+        session.finalize_handshake().unwrap();
+        let mut server_session = server.join().unwrap();
+        assert_eq!(allowlist.sessions(), 1);
+
+        // Removing the client's key closes the link.
+        assert!(allowlist.remove(&client_key));
+        assert!(server_session.recv_command().is_err());
+        assert!(session.recv_command().is_err());
+        assert_eq!(allowlist.sessions(), 0);
+
+        // A peer removed during its handshake gets no session, even
+        // when established sessions are left open.
+        let (client_config, server_config) = test_configs();
+        let allowlist = match server_config.authenticator {
+            PeerAuthenticator::Provider(ref state) => state.client_map.clone(),
+            _ => unreachable!(),
+        };
+        let client_key = client_config.authentication_key.public_key();
+        let (client_stream, server_stream) = duplex();
+        let server = thread::spawn(move|| {
+            let mut session = Session::new(server_config, false).unwrap();
+            session.initialize(server_stream).unwrap();
+            session
+        });
+        let mut session = Session::new(client_config, true).unwrap();
+        session.initialize(client_stream).unwrap();
+        let server_session = server.join().unwrap();
+        assert!(allowlist.remove(&client_key));
+        match server_session.into_transport_mode() {
+            Err(HandshakeError::PeerRevokedError) => {},
+            _ => panic!("expected a revoked peer"),
+        }
+    }
+
+    #[test]
+    fn pinned_keys_test() {
+        let (mut client_config, server_config) = test_configs();
+        let mut rng = OsRng::new().expect("failure to create an OS RNG");
+        let old_key = PrivateKey::generate(&mut rng).unwrap().public_key();
+        let server_key = client_config.peer_public_key.take().unwrap();
+        if let PeerAuthenticator::Client(ref mut state) = client_config.authenticator {
+            state.peer_public_keys = vec![old_key.clone(), server_key];
+        }
+        let (client_stream, server_stream) = duplex();
+
+        let server = thread::spawn(move|| {
+            let mut session = Session::new(server_config, false).unwrap();
+            session.initialize(server_stream).unwrap();
+            session = session.into_transport_mode().unwrap();
+            session.finalize_handshake().unwrap();
+        });
+
+        let mut session = Session::new(client_config.clone(), true).unwrap();
+        session.initialize(client_stream).unwrap();
+        assert_eq!(session.matched_key(), Some(1));
+        session = session.into_transport_mode().unwrap();
+        session.finalize_handshake().unwrap();
+        server.join().unwrap();
+
+        // A server key which is not pinned is rejected.
+        if let PeerAuthenticator::Client(ref mut state) = client_config.authenticator {
+            state.peer_public_keys = vec![old_key];
+        }
+        let (client_stream, server_stream) = duplex();
+        let (_, server_config) = test_configs();
+        let server = thread::spawn(move|| {
+            let mut session = Session::new(server_config, false).unwrap();
+            session.initialize(server_stream).is_err()
+        });
+        let mut session = Session::new(client_config, true).unwrap();
+        match session.initialize(client_stream) {
+            Err(HandshakeError::ClientHandshakeError(ClientHandshakeError::AuthenticationError)) => {},
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(session.matched_key(), None);
+        drop(session);
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn psk_mismatch_test() {
+        let (mut client_config, mut server_config) = test_configs();
+        client_config.psk = Some(Zeroizing::new([1u8; 32]));
+        server_config.psk = Some(Zeroizing::new([2u8; 32]));
+        let (client_stream, server_stream) = duplex();
+
+        let server = thread::spawn(move|| {
+            let mut session = Session::new(server_config, false).unwrap();
+            match session.initialize(server_stream) {
+                Err(HandshakeError::ServerHandshakeError(ServerHandshakeError::DecryptError)) => {},
+                x => panic!("unexpected result {:?}", x),
             }
-        }));
+        });
+        // The initiator cannot tell a different key from any other
+        // rejection, the responder just closes the link.
+        let mut session = Session::new(client_config, true).unwrap();
+        session.initialize(client_stream).unwrap();
+        let mut session = session.into_transport_mode().unwrap();
+        server.join().unwrap();
+        assert!(session.finalize_handshake().is_err());
+    }
 
-        // wait for spawned threads to exit
-        for t in threads {
-            let _ = t.join();
+    #[test]
+    fn rate_limit_test() {
+        let (client_config, mut server_config) = test_configs();
+        let mut limits = RateLimitConfig {
+            packets: TokenBucketConfig { rate: 0, burst: 2 },
+            control: TokenBucketConfig { rate: 1, burst: 10 },
+            policy: RateLimitPolicy::Drop,
+        };
+        server_config.rate_limits.client = Some(limits);
+        match Session::new(server_config.clone(), false) {
+            Err(HandshakeError::InvalidRateLimitError) => {},
+            _ => panic!("expected an invalid rate limit"),
         }
+        limits.packets.rate = 1;
+        server_config.rate_limits.client = Some(limits);
+        let (client_stream, server_stream) = duplex();
+
+        let client = thread::spawn(move|| {
+            let mut session = Session::new(client_config, true).unwrap();
+            session.initialize(client_stream).unwrap();
+            session = session.into_transport_mode().unwrap();
+            session.finalize_handshake().unwrap();
+            for _ in 0..5 {
+                session.send_command(&Command::SendPacket { sphinx_packet: vec![1, 2, 3] }).unwrap();
+            }
+            session.send_command(&Command::RetrieveMessage { sequence: 7 }).unwrap();
+        });
+
+        let mut session = Session::new(server_config, false).unwrap();
+        session.initialize(server_stream).unwrap();
+        session = session.into_transport_mode().unwrap();
+        session.finalize_handshake().unwrap();
+        for _ in 0..2 {
+            match session.recv_command().unwrap() {
+                Command::SendPacket { .. } => {},
+                _ => panic!("unexpected command"),
+            }
+        }
+        assert_eq!(session.recv_command().unwrap(), Command::RetrieveMessage { sequence: 7 });
+        assert_eq!(session.rate_limited_commands(), 3);
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn metrics_test() {
+        let (mut client_config, mut server_config) = test_configs();
+        let client_metrics = InMemoryMetrics::new();
+        let server_metrics = InMemoryMetrics::new();
+        client_config.metrics = Some(Arc::new(client_metrics.clone()));
+        server_config.metrics = Some(Arc::new(server_metrics.clone()));
+        let second_server_config = server_config.clone();
+        let (client_stream, server_stream) = duplex();
+
+        let server = thread::spawn(move|| {
+            let mut session = Session::new(server_config, false).unwrap();
+            session.initialize(server_stream).unwrap();
+            session = session.into_transport_mode().unwrap();
+            session.finalize_handshake().unwrap();
+            session.recv_command().unwrap();
+        });
+        let mut session = Session::new(client_config.clone(), true).unwrap();
+        session.initialize(client_stream).unwrap();
+        session = session.into_transport_mode().unwrap();
+        session.finalize_handshake().unwrap();
+        session.send_command(&Command::SendPacket { sphinx_packet: vec![0u8; 100] }).unwrap();
+        server.join().unwrap();
+
+        let client = client_metrics.snapshot();
+        let server = server_metrics.snapshot();
+        assert_eq!(server.handshakes_completed, 1);
+        assert_eq!(server.commands_sent.get("NoOp"), Some(&1));
+        assert_eq!(server.commands_received.get("SendPacket"), Some(&1));
+        assert_eq!(client.commands_received.get("NoOp"), Some(&1));
+        assert_eq!(server.bytes_received, client.bytes_sent);
+        assert_eq!(client.bytes_received, server.bytes_sent);
+
+        // The server does not know the client's new key.
+        let mut rng = OsRng::new().expect("failure to create an OS RNG");
+        client_config.authentication_key = PrivateKey::generate(&mut rng).unwrap().into();
+        let (client_stream, server_stream) = duplex();
+        let client = thread::spawn(move|| {
+            let mut session = Session::new(client_config, true).unwrap();
+            let _ = session.initialize(client_stream);
+        });
+        let mut session = Session::new(second_server_config, false).unwrap();
+        assert!(session.initialize(server_stream).is_err());
+        client.join().unwrap();
+        assert_eq!(server_metrics.snapshot().handshakes_failed.get("AuthenticationError"), Some(&1));
     }
 }
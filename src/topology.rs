@@ -0,0 +1,225 @@
+// topology.rs - stratified mix network topology
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use ecdh_wrapper::PublicKey;
+
+use super::errors::TopologyError;
+
+
+/// Layer is the position of a node in a stratified topology.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Layer {
+    Provider,
+    Mix(u8),
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Layer::Provider => write!(f, "the provider layer"),
+            Layer::Mix(x) => write!(f, "mix layer {}", x),
+        }
+    }
+}
+
+struct TopologyState {
+    local_layer: Layer,
+    layers: HashMap<PublicKey, Layer>,
+}
+
+/// Topology holds the layer assignments of a stratified mix network
+/// and the layer of the local node. A node accepts inbound links
+/// only from the layer before its own: the first mix layer from
+/// Providers and Providers from the last mix layer. Outbound links
+/// go to the layer after its own. Clones share the same assignments.
+#[derive(Clone)]
+pub struct Topology {
+    state: Arc<Mutex<TopologyState>>,
+}
+
+impl Topology {
+    pub fn new(local_layer: Layer) -> Topology {
+        Topology {
+            state: Arc::new(Mutex::new(TopologyState {
+                local_layer,
+                layers: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn local_layer(&self) -> Layer {
+        self.state.lock().unwrap().local_layer
+    }
+
+    pub fn set_local_layer(&self, layer: Layer) {
+        self.state.lock().unwrap().local_layer = layer;
+    }
+
+    pub fn layer(&self, key: &PublicKey) -> Option<Layer> {
+        self.state.lock().unwrap().layers.get(key).cloned()
+    }
+
+    pub fn set_layer(&self, key: PublicKey, layer: Layer) {
+        self.state.lock().unwrap().layers.insert(key, layer);
+    }
+
+    pub fn remove(&self, key: &PublicKey) -> bool {
+        self.state.lock().unwrap().layers.remove(key).is_some()
+    }
+
+    /// Atomically replace all layer assignments, e.g. with those of
+    /// a new consensus.
+    pub fn replace<I: IntoIterator<Item = (PublicKey, Layer)>>(&self, assignments: I) {
+        let layers = assignments.into_iter().collect();
+        self.state.lock().unwrap().layers = layers;
+    }
+
+    /// Returns the layer allowed to connect to the local node.
+    pub fn inbound_layer(&self) -> Layer {
+        let state = self.state.lock().unwrap();
+        inbound_layer(&state)
+    }
+
+    /// Returns the layer the local node connects to.
+    pub fn outbound_layer(&self) -> Layer {
+        let state = self.state.lock().unwrap();
+        outbound_layer(&state)
+    }
+
+    /// Check that the given peer may connect to the local node.
+    pub fn check_inbound(&self, key: &PublicKey) -> Result<(), TopologyError> {
+        let state = self.state.lock().unwrap();
+        check_layer(&state, key, inbound_layer(&state))
+    }
+
+    /// Check that the local node may connect to the given peer.
+    pub fn check_outbound(&self, key: &PublicKey) -> Result<(), TopologyError> {
+        let state = self.state.lock().unwrap();
+        check_layer(&state, key, outbound_layer(&state))
+    }
+}
+
+fn check_layer(state: &TopologyState, key: &PublicKey, expected: Layer) -> Result<(), TopologyError> {
+    let layer = match state.layers.get(key) {
+        Some(x) => *x,
+        None => return Err(TopologyError::UnknownPeerError),
+    };
+    if layer != expected {
+        return Err(TopologyError::WrongLayerError(layer, expected))
+    }
+    Ok(())
+}
+
+fn last_mix_layer(state: &TopologyState) -> u8 {
+    state.layers.values().filter_map(|x| match *x {
+        Layer::Mix(x) => Some(x),
+        Layer::Provider => None,
+    }).max().unwrap_or(0)
+}
+
+fn inbound_layer(state: &TopologyState) -> Layer {
+    match state.local_layer {
+        Layer::Mix(0) => Layer::Provider,
+        Layer::Mix(x) => Layer::Mix(x - 1),
+        Layer::Provider => Layer::Mix(last_mix_layer(state)),
+    }
+}
+
+fn outbound_layer(state: &TopologyState) -> Layer {
+    match state.local_layer {
+        Layer::Mix(x) if x >= last_mix_layer(state) => Layer::Provider,
+        Layer::Mix(x) => Layer::Mix(x + 1),
+        Layer::Provider => Layer::Mix(0),
+    }
+}
+
+impl PartialEq for Topology {
+    fn eq(&self, other: &Topology) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl fmt::Debug for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("Topology")
+            .field("local_layer", &state.local_layer)
+            .field("layers", &state.layers)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use self::rand::os::OsRng;
+    use ecdh_wrapper::PrivateKey;
+
+    use super::*;
+    use super::super::messages::{PeerAuthenticator, PeerCredentials, TopologyAuthenticatorState};
+
+    #[test]
+    fn topology_test() {
+        let mut rng = OsRng::new().unwrap();
+        let provider = PrivateKey::generate(&mut rng).unwrap().public_key();
+        let mix0 = PrivateKey::generate(&mut rng).unwrap().public_key();
+        let mix1 = PrivateKey::generate(&mut rng).unwrap().public_key();
+        let stranger = PrivateKey::generate(&mut rng).unwrap().public_key();
+
+        let topology = Topology::new(Layer::Mix(1));
+        topology.replace(vec![
+            (provider.clone(), Layer::Provider),
+            (mix0.clone(), Layer::Mix(0)),
+            (mix1.clone(), Layer::Mix(1)),
+        ]);
+        assert_eq!(topology.check_inbound(&mix0), Ok(()));
+        assert_eq!(topology.check_inbound(&provider), Err(TopologyError::WrongLayerError(Layer::Provider, Layer::Mix(0))));
+        assert_eq!(topology.check_inbound(&stranger), Err(TopologyError::UnknownPeerError));
+
+        assert_eq!(topology.check_outbound(&provider), Ok(()));
+        assert_eq!(topology.check_outbound(&mix0), Err(TopologyError::WrongLayerError(Layer::Mix(0), Layer::Provider)));
+
+        topology.set_local_layer(Layer::Mix(0));
+        assert_eq!(topology.check_inbound(&provider), Ok(()));
+        assert_eq!(topology.check_outbound(&mix1), Ok(()));
+        topology.set_local_layer(Layer::Provider);
+        assert_eq!(topology.check_inbound(&mix1), Ok(()));
+        assert_eq!(topology.check_outbound(&mix0), Ok(()));
+
+        let mut authenticator = PeerAuthenticator::Topology(TopologyAuthenticatorState {
+            topology: topology.clone(),
+            mix_ad_policy: Default::default(),
+            outbound: false,
+        });
+        let creds = PeerCredentials {
+            additional_data: vec![],
+            public_key: mix0,
+        };
+        assert!(!authenticator.is_peer_valid(&creds));
+        assert_eq!(authenticator.topology_error(&creds), Some(TopologyError::WrongLayerError(Layer::Mix(0), Layer::Mix(1))));
+
+        // The Provider links out to the first mix layer.
+        if let PeerAuthenticator::Topology(ref mut state) = authenticator {
+            state.outbound = true;
+        }
+        assert!(authenticator.is_peer_valid(&creds));
+    }
+}
@@ -0,0 +1,285 @@
+// transport.rs - byte stream transports for sessions
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, Shutdown};
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+
+
+/// Transport is a reliable, ordered byte stream a session runs over.
+pub trait Transport: Read + Write + Send {
+    /// Returns another handle to the same underlying stream, used to
+    /// give the reading and writing halves of a session their own handle.
+    fn try_clone_transport(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// Closes both directions of the stream.
+    fn shutdown_transport(&self) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone_transport(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown_transport(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+impl Transport for Box<dyn Transport> {
+    fn try_clone_transport(&self) -> io::Result<Box<dyn Transport>> {
+        (**self).try_clone_transport()
+    }
+
+    fn shutdown_transport(&self) -> io::Result<()> {
+        (**self).shutdown_transport()
+    }
+}
+
+/// NetworkConditions describes the faults injected into one direction
+/// of a simulated link. The default is a perfect link.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConditions {
+    /// Delay before written bytes become readable. Delivery stays in
+    /// order, a write is never overtaken by a later one.
+    pub latency: Duration,
+    /// Close the link after this many bytes have been sent, dropping
+    /// the remainder of the write that crosses the limit.
+    pub truncate_after: Option<usize>,
+    /// Flip the lowest bit of the bytes at these stream offsets.
+    pub bit_flips: Vec<usize>,
+    /// Block writes once this many bytes have been sent, until the
+    /// link is closed, like a peer which stopped reading.
+    pub stall_after: Option<usize>,
+}
+
+struct PipeState {
+    chunks: VecDeque<(Instant, Vec<u8>)>,
+    sent: usize,
+    closed: bool,
+    conditions: NetworkConditions,
+}
+
+/// One direction of an in-memory link.
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+impl Pipe {
+    fn new(conditions: NetworkConditions) -> Pipe {
+        Pipe {
+            state: Mutex::new(PipeState {
+                chunks: VecDeque::new(),
+                sent: 0,
+                closed: false,
+                conditions,
+            }),
+            readable: Condvar::new(),
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        while let Some(limit) = state.conditions.stall_after {
+            if state.closed || state.sent < limit {
+                break
+            }
+            state = self.readable.wait(state).unwrap();
+        }
+        if state.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "duplex stream closed"));
+        }
+        let mut chunk = buf.to_vec();
+        let mut truncated = false;
+        if let Some(limit) = state.conditions.truncate_after {
+            if state.sent + chunk.len() >= limit {
+                chunk.truncate(limit - state.sent);
+                truncated = true;
+            }
+        }
+        for offset in &state.conditions.bit_flips {
+            if *offset >= state.sent && *offset < state.sent + chunk.len() {
+                chunk[*offset - state.sent] ^= 1;
+            }
+        }
+        state.sent += chunk.len();
+        let deliver_at = Instant::now() + state.conditions.latency;
+        if !chunk.is_empty() {
+            state.chunks.push_back((deliver_at, chunk));
+        }
+        if truncated {
+            state.closed = true;
+        }
+        self.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let wait = match state.chunks.front() {
+                Some(&(deliver_at, _)) if deliver_at <= now => None,
+                Some(&(deliver_at, _)) => Some(deliver_at - now),
+                None if state.closed => return Ok(0),
+                None => Some(Duration::from_secs(3600)),
+            };
+            match wait {
+                None => break,
+                Some(timeout) => {
+                    state = self.readable.wait_timeout(state, timeout).unwrap().0;
+                },
+            }
+        }
+        let n;
+        let consumed = {
+            let chunk = &mut state.chunks.front_mut().unwrap().1;
+            n = cmp::min(buf.len(), chunk.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            chunk.drain(..n);
+            chunk.is_empty()
+        };
+        if consumed {
+            state.chunks.pop_front();
+        }
+        Ok(n)
+    }
+}
+
+/// DuplexStream is one end of an in-memory link created by `duplex`
+/// or `simulated_duplex`. The link is closed when either end calls
+/// `shutdown_transport` or when every handle to an end is dropped.
+pub struct DuplexStream {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    handles: Arc<()>,
+}
+
+/// Create a connected pair of in-memory streams.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    simulated_duplex(NetworkConditions::default(), NetworkConditions::default())
+}
+
+/// Create a connected pair of in-memory streams, injecting the given
+/// faults into the traffic from the first to the second end and from
+/// the second to the first end respectively.
+pub fn simulated_duplex(a_to_b: NetworkConditions, b_to_a: NetworkConditions) -> (DuplexStream, DuplexStream) {
+    let a_to_b = Arc::new(Pipe::new(a_to_b));
+    let b_to_a = Arc::new(Pipe::new(b_to_a));
+    let a = DuplexStream {
+        incoming: b_to_a.clone(),
+        outgoing: a_to_b.clone(),
+        handles: Arc::new(()),
+    };
+    let b = DuplexStream {
+        incoming: a_to_b,
+        outgoing: b_to_a,
+        handles: Arc::new(()),
+    };
+    (a, b)
+}
+
+impl DuplexStream {
+    fn clone_handle(&self) -> DuplexStream {
+        DuplexStream {
+            incoming: self.incoming.clone(),
+            outgoing: self.outgoing.clone(),
+            handles: self.handles.clone(),
+        }
+    }
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.incoming.read(buf)
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for DuplexStream {
+    fn try_clone_transport(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.clone_handle()))
+    }
+
+    fn shutdown_transport(&self) -> io::Result<()> {
+        self.incoming.close();
+        self.outgoing.close();
+        Ok(())
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.handles) == 1 {
+            let _ = self.shutdown_transport();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplex_test() {
+        let (mut a, mut b) = duplex();
+        a.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let conditions = NetworkConditions {
+            latency: Duration::from_millis(20),
+            truncate_after: Some(6),
+            bit_flips: vec![1],
+            stall_after: None,
+        };
+        let (mut a, mut b) = simulated_duplex(conditions, NetworkConditions::default());
+        let start = Instant::now();
+        a.write_all(b"abcd").unwrap();
+        a.write_all(b"efgh").unwrap();
+        let mut buf = vec![];
+        b.read_to_end(&mut buf).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(&buf[..], b"accdef");
+        assert!(a.write_all(b"more").is_err());
+
+        // Dropping the last handle of an end closes the link.
+        let (a, mut b) = duplex();
+        let a2 = a.try_clone_transport().unwrap();
+        drop(a);
+        drop(a2);
+        assert_eq!(b.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+}